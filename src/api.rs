@@ -1,22 +1,29 @@
-use std::{collections::HashMap, convert::Infallible};
+use std::{collections::HashMap, convert::Infallible, sync::atomic::Ordering};
 
+use anyhow::Context;
 use bitcoin_hashes::Hash;
 
 use lightning_invoice::Invoice;
 use serde_json::json;
 
-use tonic_lnd::lnrpc::invoice::InvoiceState;
-use tracing::{debug, error, info, instrument};
+use tonic_lnd::{
+    lnrpc::invoice::InvoiceState,
+    tonic::{Code, Status},
+};
+use tracing::{debug, error, info, instrument, warn};
 use warp::{
-    hyper::{HeaderMap, StatusCode},
-    reject, Rejection, Reply,
+    http::HeaderValue,
+    hyper::{body::Bytes, header, HeaderMap, StatusCode},
+    reject, Filter, Rejection, Reply,
 };
 
 use crate::{
-    config::{Backend, Config},
+    cache::{self, ResponseCache},
+    config::{Backend, Config, Persistence, Tokens},
     db, lnd,
     lsat::{self, HeadersParser, MiliSats, ToSha256},
-    upstream::Upstream,
+    pool::InvoicePool,
+    upstream::{Caller, Upstream, UpstreamError},
 };
 
 #[derive(Debug)]
@@ -27,11 +34,71 @@ impl reject::Reject for MyRejection<'static> {}
 struct Nope;
 impl warp::reject::Reject for Nope {}
 
-#[instrument(level = "info", skip(_config, lnd))]
+/// Service the request depends on (e.g. the LND node) is not available
+#[derive(Debug)]
+struct Unavailable(&'static str);
+impl reject::Reject for Unavailable {}
+
+/// Backend exists for the path, but it doesn't accept the method
+#[derive(Debug)]
+pub struct MethodNotAllowed(pub Vec<String>);
+impl reject::Reject for MethodNotAllowed {}
+
+/// Request data passed to the upstream: query parameters merged
+/// with the JSON body, if there's one (GET requests usually have none)
+pub fn with_indata(
+) -> impl Filter<Extract = (HashMap<String, String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and(warp::body::bytes())
+        .and_then(|mut indata: HashMap<String, String>, body: Bytes| async move {
+            if !body.is_empty() {
+                let json: HashMap<String, String> =
+                    serde_json::from_slice(&body).map_err(|e| {
+                        error!(error=%e, "Unable to parse request body");
+                        MyRejection("Request body should be a JSON object")
+                    })?;
+                indata.extend(json);
+            }
+            Ok::<_, Rejection>(indata)
+        })
+}
+
+/// Upstream failure, mapped to a status code and message
+/// that make sense to the client of the proxy.
+#[derive(Debug)]
+struct UpstreamRejection {
+    status: StatusCode,
+    message: String,
+}
+impl reject::Reject for UpstreamRejection {}
+
+/// Convert an error from the upstream call into a rejection,
+/// keeping the upstream status & message where we have them.
+fn upstream_rejection(backend: &Backend, err: anyhow::Error) -> Rejection {
+    match err.downcast_ref::<UpstreamError>() {
+        Some(e) => {
+            error!(backend = backend.name, error = %e, "Upstream request failed");
+            if let UpstreamError::Status { message, .. } = e {
+                debug!(backend = backend.name, message, "Upstream error response");
+            }
+            reject::custom(UpstreamRejection {
+                status: e.proxy_status(),
+                message: e.message(),
+            })
+        }
+        None => {
+            error!(backend = backend.name, error = %err, "Unable to handle upstream request");
+            reject::custom(Nope)
+        }
+    }
+}
+
+#[instrument(level = "info", skip(_config, lnd, store))]
 pub async fn handle_invoice_status(
     _config: Config,
     indata: HashMap<String, String>,
     lnd: lnd::Client,
+    store: db::Store,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let inv: Invoice = indata
         .get("invoice")
@@ -45,17 +112,32 @@ pub async fn handle_invoice_status(
             MyRejection("Unable to parse invoice")
         })?;
 
+    // settled invoices are known without asking LND
+    let settlement = store
+        .settlement(&hex::encode(inv.payment_hash().into_inner()))
+        .map_err(|e| {
+            error!(error=%e, "Unable to get invoice settlement");
+            MyRejection("Unable to find invoice")
+        })?;
+    if let Some(settlement) = settlement {
+        let resp = json!({
+            "preimage": hex::encode(settlement.preimage),
+            "state": InvoiceState::Settled as i32,
+        });
+        return Ok(warp::reply::json(&resp).into_response());
+    }
+
     let ph = lnd::PaymentHash {
         r_hash: inv.payment_hash().to_vec(),
         ..Default::default()
     };
 
-    let inv = lnd.lookup_invoice(ph).await.map_err(|e| {
+    let (node, inv) = lnd.lookup_invoice(ph, None).await.map_err(|e| {
         error!(status=%e, "Provided invoice not found");
         MyRejection("Unable to find invoice")
     })?;
 
-    info!(state=?inv.state(), "retrived invoice state");
+    info!(node, state=?inv.state(), "retrived invoice state");
 
     let resp = json!({
         "preimage": hex::encode(inv.r_preimage),
@@ -64,23 +146,77 @@ pub async fn handle_invoice_status(
     Ok(warp::reply::json(&resp).into_response())
 }
 
-#[instrument(level = "info", skip(lnd))]
+/// Health of the proxy dependencies and the store maintenance
+pub async fn handle_status(lnd: lnd::Client) -> Result<impl warp::Reply, warp::Rejection> {
+    let metrics = &db::sweeper::METRICS;
+    let resp = json!({
+        "lnd": lnd.status(),
+        "sweeper": {
+            "runs": metrics.runs.load(Ordering::Relaxed),
+            "unpaid_removed": metrics.unpaid_removed.load(Ordering::Relaxed),
+            "paid_removed": metrics.paid_removed.load(Ordering::Relaxed),
+            "settlements_removed": metrics.settlements_removed.load(Ordering::Relaxed),
+        },
+    });
+    let code = match lnd.health() {
+        lnd::Health::Down => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&resp), code))
+}
+
+#[allow(clippy::too_many_arguments)] // one per extracted filter
+#[instrument(level = "info", skip(lnd, store, cache, pool))]
 pub async fn handle_protected(
     backend: Backend,
     indata: HashMap<String, String>,
     headers: HeaderMap,
+    tokens: Tokens,
     lnd: lnd::Client,
+    store: db::Store,
+    cache: ResponseCache,
+    pool: InvoicePool,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     debug!(headers=?headers, indata=?indata, "Handling protected resource");
 
+    // free routes (e.g. a GET with a method price of 0) are proxied
+    // directly, a challenge for a zero amount invoice makes no sense
+    if backend.price_msat == 0 {
+        return serve_free(&backend, &indata, &cache).await;
+    }
+
     if !headers.contains_key("Authorization") {
+        // no invoice can be issued without a node, pooled ones included
+        if !lnd.is_reachable() {
+            error!("LND is down, unable to issue a challenge");
+            return Err(Unavailable("Lightning node unavailable").into());
+        }
+        if !lnd.can_receive(&backend.amount_total()) {
+            error!("Not enough inbound liquidity, unable to issue a challenge");
+            return Err(Unavailable("Not enough inbound liquidity").into());
+        }
         let indata_sha = indata.to_sha256().unwrap();
-        return lsat::Lsat::generate_challange(lnd, &backend, &indata_sha)
-            .await
-            .map_err(|e| {
-                error!(error=%e, "Unable to generate auth header");
-                MyRejection("Unable to generate challange").into()
-            });
+        return lsat::Lsat::generate_challange(
+            lnd,
+            &pool,
+            &store,
+            &tokens,
+            &backend,
+            &indata_sha,
+        )
+        .await
+        .map_err(|e| -> Rejection {
+            error!(error=%e, "Unable to generate auth header");
+            match e.downcast_ref::<Status>() {
+                Some(s) if s.code() == Code::ResourceExhausted => {
+                    Unavailable("Not enough inbound liquidity").into()
+                }
+                Some(s) if lnd::is_connection_error(s) => {
+                    Unavailable("Lightning node unavailable").into()
+                }
+                _ => MyRejection("Unable to generate challange").into(),
+            }
+        });
     }
 
     let (lsat, preimage) = headers.parse_lsat().map_err(|e| {
@@ -88,29 +224,40 @@ pub async fn handle_protected(
         MyRejection("LSAT incorrect")
     })?;
 
-    let mut entry = db::Entry::get(&lsat.id).await.map_err(|e| {
-        error!(error=%e, "No lsat found in the database for id");
-        MyRejection("No db entry for LSAT, possibly expired")
-    })?;
+    let stored = db::Entry::key(&lsat.id)
+        .and_then(|key| store.get(&key))
+        .map_err(|e| {
+            error!(error=%e, "Unable to get LSAT from the database");
+            MyRejection("Unable to get LSAT")
+        })?;
+
+    // with lazy persistence the entry doesn't exist until the
+    // first redemption, secret is recovered from the root key
+    let secret = match &stored {
+        Some(entry) => entry.secret().map_err(|e| {
+            error!(error=%e, "Unable to read LSAT secret");
+            MyRejection("Unable to get LSAT")
+        })?,
+        None if tokens.persistence == Persistence::Lazy => {
+            lsat::derive_secret(&lsat.id, tokens.root_key.as_ref()).map_err(|e| {
+                error!(error=%e, "Unable to derive LSAT secret");
+                MyRejection("LSAT incorrect")
+            })?
+        }
+        None => {
+            error!("No lsat found in the database for id");
+            return Err(MyRejection("No db entry for LSAT, possibly expired").into());
+        }
+    };
 
     let indata_sha = indata.to_sha256().unwrap();
-    lsat.verify(&entry.secret(), &backend.path, indata_sha)
+    lsat.verify(&secret, tokens.root_key.as_ref(), &backend.request_path, indata_sha)
         .await
         .map_err(|e| {
             error!(error=%e, "LSAT macaroon verification failed");
             MyRejection("LSAT incorrect")
         })?;
 
-    // update quota / user budget
-    entry.quota -= backend.get_price();
-    match entry.quota {
-        MiliSats(0) => {
-            info!("Avaiable budget exhausted, removing entry from DB");
-            entry.remove().await;
-        }
-        _ => entry.update().await.unwrap(),
-    }
-
     let preimage_sha = preimage.to_sha256().map_err(|e| {
         error!(error=%e, "Can't get preimage sha");
         MyRejection("Problem calculating sha256")
@@ -121,58 +268,218 @@ pub async fn handle_protected(
         return Err(MyRejection("Preimage does not match payment hash").into());
     }
 
-    // TODO: verify invoice status
-    // TODO: should be a pre-cached cache db call instead
-    // with API call fallback if we're not aware of such invoice
     debug!(
         "Getting invoice state for preimage: {:?}",
         hex::encode(preimage.0)
     );
 
-    let ph = lnd::PaymentHash {
-        r_hash: preimage
-            .to_sha256()
-            .expect("this is hashable for sure")
-            .to_vec(),
-        ..Default::default()
+    // settlements are recorded locally, so tokens paid for keep being
+    // served while LND is unreachable, only unrecorded payments need it
+    let r_hash = preimage.to_sha256().expect("this is hashable for sure");
+    let settlement = lnd
+        .find_settlement(
+            &store,
+            &r_hash.into_inner(),
+            stored.as_ref().and_then(|e| e.node.as_deref()),
+        )
+        .await
+        .map_err(|e| -> Rejection {
+            error!(error=%e, "Unable to get invoice state");
+            match e.downcast_ref::<Status>() {
+                Some(s) if lnd::is_connection_error(s) => {
+                    Unavailable("Lightning node unavailable").into()
+                }
+                _ => MyRejection("Unable to get invoice state").into(),
+            }
+        })?
+        .ok_or_else(|| {
+            error!("Invoice is not settled!");
+            MyRejection("Invoice is not settled")
+        })?;
+
+    // token stays valid for `token_ttl` after the payment
+    let token_expiry = settlement.settle_date + backend.token_ttl;
+    let mut entry = match stored {
+        Some(entry) => entry,
+        None => {
+            let entry = db::Entry::paid(
+                &lsat.id,
+                &secret,
+                MiliSats(settlement.value_msat as u32),
+                token_expiry,
+            )
+            .and_then(|mut entry| {
+                entry.node = Some(settlement.node.clone());
+                // somebody else might have redeemed the token in the meantime
+                store.create(&entry)?;
+                store.get(entry.id())?.context("should be an entry in db")
+            })
+            .map_err(|e| {
+                error!(error=%e, "Unable to persist redeemed LSAT");
+                MyRejection("Unable to update LSAT state")
+            })?;
+            info!("First redemption of the token, entry persisted");
+            entry
+        }
+    };
+
+    // pending entries expire with their invoice, the payment has to be
+    // recorded before the expiry is checked so a token paid for but first
+    // used after the invoice expiry gets its `token_ttl`
+    if entry.state == db::PaymentState::Pending {
+        store.mark_paid(entry.id(), token_expiry).map_err(|e| {
+            error!(error=%e, "Unable to mark entry as paid");
+            MyRejection("Unable to update LSAT state")
+        })?;
+        entry.state = db::PaymentState::Paid;
+        entry.expires_at = Some(token_expiry);
+    }
+
+    if entry.is_expired(db::now()) {
+        error!("LSAT entry expired");
+        return Err(MyRejection("LSAT expired").into());
+    }
+
+    // we're finally happy after all the checks, serve identical
+    // requests from the cache or make the actual call with provided data
+    let cached = cache.get(&backend, &indata_sha);
+    let price = match cached {
+        Some(_) => cache::hit_price(&backend),
+        None => backend.get_price(),
     };
-    let inv = lnd.lookup_invoice(ph).await.map_err(|e| {
-        error!(error=%e, "Unable to get invoice state");
-        MyRejection("Unable to get invoice state")
+
+    if entry.quota.0 < price.0 {
+        error!(quota = entry.quota.0, price = price.0, "Not enough budget left");
+        return Err(MyRejection("Available budget exhausted").into());
+    }
+
+    // the price is reserved before the call, so concurrent requests can't
+    // all spend the same budget, it's refunded when the call fails
+    let left = store.debit(entry.id(), &price).map_err(|e| {
+        error!(error=%e, "Unable to reserve the price");
+        MyRejection("Available budget exhausted")
     })?;
+    let refund = |amount: &MiliSats| match store.refund(entry.id(), amount) {
+        Ok(quota) => quota,
+        Err(e) => {
+            error!(error=%e, amount = amount.0, "Unable to refund the reserved price");
+            left.clone()
+        }
+    };
+
+    let (mut resp, charged, quota) = match cached {
+        Some(cached) => {
+            let resp = Upstream::with_response(backend.clone(), cached)
+                .respond()
+                .map_err(|e| {
+                    refund(&price);
+                    upstream_rejection(&backend, e)
+                })?;
+            (resp, price, left.clone())
+        }
+        None => {
+            let caller = Caller {
+                token_id: lsat.id.token_id(),
+                paid: MiliSats(settlement.amt_paid_msat as u32),
+                quota: entry.quota.clone(),
+                capabilities: backend.capabilties.clone(),
+            };
+            let mut upstream = Upstream::new(backend.clone());
+            upstream
+                .build(&indata)
+                .and_then(|upstream| upstream.identify(&caller))
+                .map_err(|e| {
+                    error!(backend = backend.name, error=%e, "Unable to construct upstream request");
+                    refund(&price);
+                    reject::custom(Nope)
+                })?
+                .make()
+                .await
+                .map_err(|e| {
+                    refund(&price);
+                    upstream_rejection(&backend, e)
+                })?;
 
-    if inv.state() != InvoiceState::Settled {
-        error!("Invoice is not settled!");
-        return Err(MyRejection("Invoice is not settled").into());
+            if let Some(fresh) = upstream.response() {
+                cache.insert(&backend, &indata_sha, fresh.clone()).await;
+            }
+            // read before the response is handed over to the client
+            let charge = upstream.charge();
+            let resp = upstream.respond().map_err(|e| {
+                refund(&price);
+                upstream_rejection(&backend, e)
+            })?;
+
+            // upstream can report the actual cost of the call, the
+            // difference to the reserved price is refunded or debited,
+            // we never charge more than what is left in the budget
+            let (charged, quota) = match charge {
+                Some(charge) if charge.0 < price.0 => {
+                    let quota = refund(&MiliSats(price.0 - charge.0));
+                    (charge, quota)
+                }
+                Some(charge) if charge.0 > price.0 => {
+                    let extra = MiliSats((charge.0 - price.0).min(left.0));
+                    match store.debit(entry.id(), &extra) {
+                        Ok(quota) => (MiliSats(price.0 + extra.0), quota),
+                        Err(e) => {
+                            // budget spent concurrently in the meantime
+                            warn!(
+                                error=%e,
+                                charge = charge.0,
+                                "Unable to debit the upstream charge"
+                            );
+                            (price, left.clone())
+                        }
+                    }
+                }
+                _ => (price, left.clone()),
+            };
+            (resp, charged, quota)
+        }
+    };
+
+    // entry is kept once it's used up, see `TokenStore::debit`
+    if quota == MiliSats(0) {
+        info!("Avaiable budget exhausted");
+    }
+
+    resp.headers_mut()
+        .insert("x-msats-charged", charged.into());
+    resp.headers_mut()
+        .insert("x-msats-quota", quota.into());
+    Ok(resp)
+}
+
+/// Serve a free route, no token is needed and nothing is charged
+async fn serve_free(
+    backend: &Backend,
+    indata: &HashMap<String, String>,
+    cache: &ResponseCache,
+) -> Result<warp::reply::Response, Rejection> {
+    let indata_sha = indata.to_sha256().unwrap();
+    if let Some(cached) = cache.get(backend, &indata_sha) {
+        return Upstream::with_response(backend.clone(), cached)
+            .respond()
+            .map_err(|e| upstream_rejection(backend, e));
     }
-    // we're finally happy after all the checks,
-    // make the actual call with provided data
-    let mut upstream = Upstream::new(backend.clone());
 
-    let data = upstream
-        .build(&indata)
+    let mut upstream = Upstream::new(backend.clone());
+    upstream
+        .build(indata)
         .map_err(|e| {
-            error!(error=%e, "Unable to construct upstream request");
+            error!(backend = backend.name, error=%e, "Unable to construct upstream request");
             reject::custom(Nope)
         })?
         .make()
         .await
-        .map_err(|e| {
-            error!(error=%e, "Unable to contact upstream");
-            reject::custom(Nope)
-        })?
-        .parse()
-        .map_err(|e| {
-            error!(error=%e, "Unable to parse upstream response");
-            reject::custom(Nope)
-        })?;
-
-    let paragraphs: Vec<&str> = data.trim().split("\n\n").collect();
-
-    let mut resp = warp::reply::json(&json!({ "data": paragraphs })).into_response();
-    resp.headers_mut()
-        .insert("x-msats-quota", entry.quota.into());
-    Ok(resp)
+        .map_err(|e| upstream_rejection(backend, e))?;
+    if let Some(fresh) = upstream.response() {
+        cache.insert(backend, &indata_sha, fresh.clone()).await;
+    }
+    upstream
+        .respond()
+        .map_err(|e| upstream_rejection(backend, e))
 }
 
 /// Receives a `Rejection` and tries to return a custom
@@ -180,6 +487,7 @@ pub async fn handle_protected(
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message: String;
+    let mut allow = None;
 
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
@@ -188,6 +496,16 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
         // error!(error=?e.to_string(), "Error when processing request");
         code = StatusCode::BAD_REQUEST;
         message = e.to_string();
+    } else if let Some(UpstreamRejection { status, message: e }) = err.find() {
+        code = *status;
+        message = e.to_string();
+    } else if let Some(Unavailable(e)) = err.find() {
+        code = StatusCode::SERVICE_UNAVAILABLE;
+        message = e.to_string();
+    } else if let Some(MethodNotAllowed(allowed)) = err.find() {
+        code = StatusCode::METHOD_NOT_ALLOWED;
+        message = format!("METHOD_NOT_ALLOWED, allowed: {}", allowed.join(", "));
+        allow = Some(allowed.join(", "));
     // } else if let Some(DivideByZero) = err.find() {
     //     code = StatusCode::BAD_REQUEST;
     //     message = "DIVIDE_BY_ZERO";
@@ -222,5 +540,192 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
         "message": message,
     }));
 
-    Ok(warp::reply::with_status(json, code))
+    let mut resp = warp::reply::with_status(json, code).into_response();
+    if let Some(allow) = allow.and_then(|a| HeaderValue::from_str(&a).ok()) {
+        resp.headers_mut().insert(header::ALLOW, allow);
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use lightning::ln::{PaymentHash, PaymentPreimage};
+    use macaroon::{Format, Macaroon};
+
+    use super::*;
+    use crate::{
+        config::{self, ResponseMode, UpstreamCharge},
+        db::memory::MemoryStore,
+        lnd::Node,
+    };
+
+    /// Client of a single node that failed to answer a call
+    async fn unreachable() -> lnd::Client {
+        let node = Node::mock("mock", 1);
+        node.take_down().await;
+        lnd::Client::new(vec![node])
+    }
+
+    /// Response the client gets, rejections included
+    async fn respond(result: Result<impl Reply, Rejection>) -> warp::reply::Response {
+        match result {
+            Ok(reply) => reply.into_response(),
+            Err(rejection) => handle_rejection(rejection).await.unwrap().into_response(),
+        }
+    }
+
+    #[tokio::test]
+    async fn challenges_are_refused_while_lnd_is_unreachable() {
+        let store: db::Store = Arc::new(MemoryStore::default());
+        let result = handle_protected(
+            config::fixtures::backend("gpt", "/gpt"),
+            HashMap::new(),
+            HeaderMap::new(),
+            Tokens::default(),
+            unreachable().await,
+            store.clone(),
+            ResponseCache::default(),
+            InvoicePool::default(),
+        )
+        .await;
+        assert_eq!(respond(result).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    /// Upstream answering all the calls with the status,
+    /// reporting the charge in the default charge header
+    fn upstream(status: StatusCode, charge: Option<u32>) -> String {
+        let route = warp::any().map(move || {
+            let mut resp = warp::reply::with_status("{}", status).into_response();
+            if let Some(charge) = charge {
+                let header = resp.headers_mut();
+                header.insert("X-Lsat-Charge-Msat", HeaderValue::from(charge));
+            }
+            resp
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    /// Backend at 100 mili-sats per call, letting the upstream
+    /// report charges of up to `max_msat`
+    fn backend(upstream: String, max_msat: u32) -> Backend {
+        Backend {
+            upstream,
+            upstream_charge: Some(UpstreamCharge {
+                header: "X-Lsat-Charge-Msat".to_string(),
+                max_msat,
+            }),
+            response_mode: ResponseMode::Raw,
+            request_path: "/gpt".to_string(),
+            ..config::fixtures::backend("gpt", "/gpt")
+        }
+    }
+
+    /// Token with the `quota` for the backend, its invoice settled at
+    /// `settle_date`, returns the id of its entry and the headers presenting it
+    fn token(
+        store: &db::Store,
+        backend: &Backend,
+        quota: u32,
+        settle_date: u64,
+    ) -> (String, HeaderMap) {
+        let preimage = PaymentPreimage(rand::random());
+        let id = lsat::Id::new(PaymentHash(preimage.to_sha256().unwrap().into_inner()));
+        let secret = lsat::derive_secret(&id, None).unwrap();
+        let entry = db::Entry::new(&id, &secret, MiliSats(quota), db::now() + 60).unwrap();
+        store.insert(&entry).unwrap();
+        store
+            .settle(&db::fixtures::settlement(&entry, settle_date))
+            .unwrap();
+
+        let mut mac = Macaroon::create(None, &secret, id.into()).unwrap();
+        mac.add_first_party_caveat(format!("time<{}", db::now() + 60).into());
+        mac.add_first_party_caveat(format!("path={}", backend.request_path).into());
+        let auth = format!(
+            "LSAT {}:{}",
+            mac.serialize(Format::V1).unwrap(),
+            hex::encode(preimage.0)
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&auth).unwrap());
+        (entry.id().to_string(), headers)
+    }
+
+    /// Call the backend with a token of the `quota`, returns
+    /// the response status and the quota left in the store
+    async fn call(backend: Backend, quota: u32) -> (StatusCode, u32) {
+        let store: db::Store = Arc::new(MemoryStore::default());
+        let (id, headers) = token(&store, &backend, quota, db::now());
+        let result = handle_protected(
+            backend,
+            HashMap::new(),
+            headers,
+            Tokens::default(),
+            lnd::Client::new(vec![]),
+            store.clone(),
+            ResponseCache::default(),
+            InvoicePool::default(),
+        )
+        .await;
+        let status = respond(result).await.status();
+        (status, store.get(&id).unwrap().unwrap().quota.0)
+    }
+
+    #[tokio::test]
+    async fn failed_calls_are_refunded() {
+        let backend = backend(upstream(StatusCode::INTERNAL_SERVER_ERROR, None), 1000);
+        assert_eq!(call(backend, 1000).await, (StatusCode::BAD_GATEWAY, 1000));
+    }
+
+    #[tokio::test]
+    async fn reported_charge_replaces_the_price() {
+        let cheaper = backend(upstream(StatusCode::OK, Some(40)), 1000);
+        assert_eq!(call(cheaper, 1000).await, (StatusCode::OK, 960));
+
+        let pricier = backend(upstream(StatusCode::OK, Some(250)), 1000);
+        assert_eq!(call(pricier, 1000).await, (StatusCode::OK, 750));
+    }
+
+    #[tokio::test]
+    async fn reported_charge_is_capped() {
+        // at the max charge of the backend
+        let backend = backend(upstream(StatusCode::OK, Some(5000)), 300);
+        assert_eq!(call(backend.clone(), 1000).await, (StatusCode::OK, 700));
+        // and at the budget left
+        assert_eq!(call(backend, 150).await, (StatusCode::OK, 0));
+    }
+
+    #[tokio::test]
+    async fn tokens_expire_token_ttl_after_the_payment() {
+        let backend = backend(upstream(StatusCode::OK, None), 1000);
+        let store: db::Store = Arc::new(MemoryStore::default());
+        let call = |headers| {
+            handle_protected(
+                backend.clone(),
+                HashMap::new(),
+                headers,
+                Tokens::default(),
+                lnd::Client::new(vec![]),
+                store.clone(),
+                ResponseCache::default(),
+                InvoicePool::default(),
+            )
+        };
+
+        // first used just before the `token_ttl` runs out
+        let settle_date = db::now() - backend.token_ttl + 60;
+        let (_, headers) = token(&store, &backend, 1000, settle_date);
+        assert_eq!(respond(call(headers).await).await.status(), StatusCode::OK);
+
+        // first used after it did
+        let settle_date = db::now() - backend.token_ttl - 60;
+        let (id, headers) = token(&store, &backend, 1000, settle_date);
+        assert_eq!(respond(call(headers).await).await.status(), StatusCode::BAD_REQUEST);
+        let entry = store.get(&id).unwrap().unwrap();
+        assert_eq!(entry.expires_at, Some(settle_date + backend.token_ttl));
+    }
 }
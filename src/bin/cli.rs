@@ -1,5 +1,13 @@
+use std::path::{Path, PathBuf};
+
 use ansi_term::{self, Colour};
+use anyhow::bail;
 use clap::{Parser, Subcommand};
+use lsat_proxy::{
+    config::{Config, StorageEngine},
+    db::{self, archive::Archive, schema, sealed},
+    lnd::permissions,
+};
 
 #[tokio::main]
 async fn main() {
@@ -10,6 +18,42 @@ async fn main() {
         Commands::Stats {} => {
             app_stats();
         }
+        Commands::Migrate { dry_run } => {
+            if let Err(e) = migrate(dry_run) {
+                eprintln!("{} {:#}", Colour::Red.paint("failed to migrate the store:"), e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Export { file } => {
+            if let Err(e) = export(&file) {
+                eprintln!("{} {:#}", Colour::Red.paint("failed to export the store:"), e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Import { file, engine, path } => {
+            if let Err(e) = import(&file, engine, path) {
+                eprintln!("{} {:#}", Colour::Red.paint("failed to import the archive:"), e);
+                std::process::exit(1);
+            }
+        }
+        Commands::VerifyArchive { file } => {
+            if let Err(e) = verify_archive(&file) {
+                eprintln!("{} {:#}", Colour::Red.paint("invalid archive:"), e);
+                std::process::exit(1);
+            }
+        }
+        Commands::LndPermissions { check } => {
+            if let Err(e) = lnd_permissions(check) {
+                eprintln!("{} {:#}", Colour::Red.paint("failed to check the macaroons:"), e);
+                std::process::exit(1);
+            }
+        }
+        Commands::RewrapSecrets {} => {
+            if let Err(e) = rewrap_secrets() {
+                eprintln!("{} {:#}", Colour::Red.paint("failed to re-wrap secrets:"), e);
+                std::process::exit(1);
+            }
+        }
     }
 }
 
@@ -24,6 +68,43 @@ struct Cli {
 enum Commands {
     /// gets usage stats data
     Stats {},
+    /// migrates the store to the current schema version,
+    /// the proxy does the same on startup
+    Migrate {
+        /// only list the pending migrations
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// exports all the token entries into a JSON archive
+    Export {
+        /// archive file to write
+        file: PathBuf,
+    },
+    /// imports a JSON archive into an empty store
+    Import {
+        /// archive file to read
+        file: PathBuf,
+        /// storage engine to import into, the configured one when not set
+        #[arg(long)]
+        engine: Option<StorageEngine>,
+        /// database file to import into, the configured one when not set
+        #[arg(long)]
+        path: Option<String>,
+    },
+    /// checks the integrity of a JSON archive
+    VerifyArchive {
+        /// archive file to check
+        file: PathBuf,
+    },
+    /// prints the minimal permissions of the LND macaroon
+    LndPermissions {
+        /// also compare them with the macaroons of the configured nodes
+        #[arg(long)]
+        check: bool,
+    },
+    /// encrypts all the stored secrets with the current encryption key,
+    /// run it while the proxy is stopped before retiring the old keys
+    RewrapSecrets {},
 }
 
 /// Prints out the `cli` tool banner
@@ -41,3 +122,156 @@ fn banner(quote: &str) {
 }
 
 fn app_stats() {}
+
+/// Re-wraps the stored secrets with the current key-encryption key
+fn rewrap_secrets() -> Result<(), anyhow::Error> {
+    let config = Config::load()?;
+    let encryption = config
+        .storage
+        .encryption
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("storage.encryption is not configured"))?;
+    let store = sealed::SealedStore::new(
+        db::open_engine(&config.storage)?,
+        sealed::Keyring::from_config(encryption)?,
+    );
+    let count = store.rewrap()?;
+    println!("re-wrapped {} secrets with key {}", count, encryption.key_id);
+    Ok(())
+}
+
+/// Lists the pending store migrations and applies them unless `dry_run`
+fn migrate(dry_run: bool) -> Result<(), anyhow::Error> {
+    let config = Config::load()?;
+    let store = db::open_engine(&config.storage)?;
+    println!(
+        "store schema version {}, current version {}",
+        store.schema_version()?,
+        schema::SCHEMA_VERSION
+    );
+    let pending = schema::pending(store.as_ref())?;
+    if pending.is_empty() {
+        println!("nothing to migrate");
+        return Ok(());
+    }
+    for migration in pending.iter() {
+        println!("  {}: {}", migration.version, migration.description);
+    }
+    if !dry_run {
+        let count = schema::run(store.as_ref())?;
+        println!("applied {} migrations", count);
+    }
+    Ok(())
+}
+
+/// Writes all the stored entries and settlements into the archive `file`
+fn export(file: &Path) -> Result<(), anyhow::Error> {
+    let config = Config::load()?;
+    let store = db::open_engine(&config.storage)?;
+    let archive = Archive::export(store.as_ref(), config.tokens.root_key.as_ref())?;
+    let summary = archive.verify()?;
+    archive.save(file)?;
+    println!(
+        "exported {} entries and {} settlements to {}",
+        summary.entries,
+        summary.settlements,
+        file.display()
+    );
+    Ok(())
+}
+
+/// Loads the archive `file` into an empty store, optionally
+/// different from the configured one
+fn import(
+    file: &Path,
+    engine: Option<StorageEngine>,
+    path: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let config = Config::load()?;
+    let archive = Archive::load(file)?;
+    if !archive.matches_root_key(config.tokens.root_key.as_ref()) {
+        bail!("archive was exported with a different tokens.root_key");
+    }
+
+    let mut storage = config.storage.clone();
+    if let Some(engine) = engine {
+        storage.engine = engine;
+    }
+    if let Some(path) = path {
+        storage.path = path;
+    }
+    let store = db::open_engine(&storage)?;
+    schema::run(store.as_ref())?;
+    let count = archive.import(store.as_ref())?;
+    println!("imported {} entries into {}", count, storage.path);
+    Ok(())
+}
+
+/// Checks the archive `file` and prints what's inside
+fn verify_archive(file: &Path) -> Result<(), anyhow::Error> {
+    let archive = Archive::load(file)?;
+    let summary = archive.verify()?;
+    println!("archive {} is valid", file.display());
+    println!("  schema version: {}", archive.schema_version);
+    println!(
+        "  entries: {} ({} pending, {} paid)",
+        summary.entries, summary.pending, summary.paid
+    );
+    println!("  sealed secrets: {}", summary.sealed);
+    println!("  settlements: {}", summary.settlements);
+    println!("  quota left: {} msat", summary.quota);
+    Ok(())
+}
+
+/// Prints the permissions the proxy needs and, with `check`,
+/// how the configured macaroons compare to them
+fn lnd_permissions(check: bool) -> Result<(), anyhow::Error> {
+    println!("minimal LND macaroon permissions:");
+    for req in permissions::REQUIRED.iter() {
+        println!(
+            "  {}:{} ({})",
+            req.entity,
+            req.action,
+            req.methods.join(", ")
+        );
+    }
+    let liquidity = &permissions::LIQUIDITY;
+    println!(
+        "  {}:{} ({}), only with the liquidity checks",
+        liquidity.entity,
+        liquidity.action,
+        liquidity.methods.join(", ")
+    );
+    let perms: Vec<_> = permissions::REQUIRED
+        .iter()
+        .map(|req| format!("{}:{}", req.entity, req.action))
+        .collect();
+    println!("bake the macaroon with:");
+    println!("  lncli bakemacaroon --save_to proxy.macaroon {}", perms.join(" "));
+    if !check {
+        return Ok(());
+    }
+
+    let config = Config::load()?;
+    for node in config.lnd.iter() {
+        let report = permissions::compare(
+            &permissions::granted(&node.mac_path)?,
+            &permissions::required(node),
+        );
+        let status = if !report.missing.is_empty() {
+            Colour::Red.paint("missing permissions")
+        } else if !report.excess.is_empty() {
+            Colour::Yellow.paint("more than needed")
+        } else {
+            Colour::Green.paint("minimal")
+        };
+        println!("node {} ({}): {}", node.name, node.mac_path, status);
+        for missing in report.missing.iter() {
+            println!("  missing {}", missing);
+        }
+        for excess in report.excess.iter() {
+            println!("  excess {}", excess);
+        }
+    }
+    Ok(())
+}
@@ -1,14 +1,28 @@
-use lsat_proxy::config::Backend;
-use tracing::info;
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use lsat_proxy::config::{Backend, SharedConfig};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
 
 use lsat_proxy::{
-    api::{handle_invoice_status, handle_protected, handle_rejection},
+    api::{
+        handle_invoice_status, handle_protected, handle_rejection, handle_status, with_indata,
+        MethodNotAllowed,
+    },
+    cache::ResponseCache,
     config::Config,
+    db::{self, archive::Archive},
     lnd,
+    pool::InvoicePool,
+    routes::{self, RouteError},
 };
 
 use tracing_subscriber::EnvFilter;
-use warp::{path::FullPath, Filter, hyper::HeaderMap, http::HeaderValue};
+use warp::{host::Authority, path::FullPath, Filter, hyper::{HeaderMap, Method}, http::HeaderValue};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -30,30 +44,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let config: Config = config::Config::builder()
-        // Add in `./Settings.toml`
-        .add_source(config::File::with_name("config"))
-        // Add in settings from the environment (with a prefix of APP)
-        // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
-        .add_source(config::Environment::with_prefix("APP"))
-        .build()
-        .expect("problem building the config")
-        .try_deserialize()
-        .expect("problem deserializing config");
-
+    let config = Config::load().expect("problem loading the config");
     info!("Connfiguration loaded on startup: {:?}", config);
 
-    // Connecting to LND requires only address, cert file, and macaroon file
-    let lnd_conf = config.lnd.clone();
-    let lnd_client = lnd::Client::init(lnd_conf.host, lnd_conf.tls_path, lnd_conf.mac_path).await;
+    for node in config.lnd.iter() {
+        lnd::permissions::check(node).expect("LND macaroon permissions check failed");
+    }
+
+    // Connecting to LND requires only address, cert file, and macaroon file,
+    // the connection is established in the background
+    let lnd_client = lnd::Client::init(&config.lnd);
+
+    let store = db::open(&config.storage).expect("failed to open token store");
 
     info!("Spinning up streaming listener for LND RPC");
-    let lnd_conf = config.lnd.clone();
-    let lnd_stream = lnd::Client::init(lnd_conf.host, lnd_conf.tls_path, lnd_conf.mac_path).await;
-    lnd_stream.subscribe_invoices().await;
+    lnd_client.subscribe_invoices(store.clone()).await;
+    {
+        let (lnd, store) = (lnd_client.clone(), store.clone());
+        tokio::spawn(async move {
+            if let Err(e) = lnd.reconcile_pending(&store).await {
+                error!(error=%e, "Unable to reconcile pending invoices");
+            }
+        });
+    }
 
-    let info = lnd_client.get_info().await.expect("failed to get info");
-    info!("LND Instance Info: {:#?}", info);
+    db::sweeper::spawn(
+        store.clone(),
+        Duration::from_secs(config.storage.sweep_interval),
+        config.max_token_ttl(),
+    );
+    let cache = ResponseCache::new(&config.backends).expect("failed to set up response cache");
+    let pool = InvoicePool::new(&config.backends, lnd_client.clone());
 
     info!("Listening on {}:{}", config.server.host, config.server.port);
 
@@ -61,29 +82,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     headers.insert("Access-Control-Expose-Headers", HeaderValue::from_static("*"));
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "DELETE"])
+        .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
         .allow_headers(vec!["accept-authenticate", "content-type", "authorization"]);
     
-    let base = warp::any().and(with_clone(config.clone()));
+    let shared_config: SharedConfig = Arc::new(RwLock::new(config.clone()));
+    reload_on_hangup(shared_config.clone())?;
+    export_on_signal(shared_config.clone(), store.clone())?;
+
+    let base = warp::any().and(with_config(shared_config.clone()));
 
     let invoice_status = base
         .clone()
         .and(warp::path!("invoice" / "status"))
         .and(warp::body::json())
         .and(with_clone(lnd_client.clone()))
+        .and(with_clone(store.clone()))
         .and_then(handle_invoice_status);
 
+    let status = warp::get()
+        .and(warp::path!("status"))
+        .and(with_clone(lnd_client.clone()))
+        .and_then(handle_status);
+
     let protected = base
         .clone()
+        .and(warp::host::optional())
+        .and(warp::method())
         .and(warp::path::full())
         .and_then(protected_path)
-        .and(warp::body::json())
+        .and(with_indata())
         .and(warp::header::headers_cloned())
+        .and(with_config(shared_config.clone()).map(|c: Config| c.tokens))
         .and(with_clone(lnd_client.clone()))
+        .and(with_clone(store))
+        .and(with_clone(cache))
+        .and(with_clone(pool))
         .and_then(handle_protected);
 
     let routes = warp::any()
         .and(invoice_status)
+        .or(status)
         .or(protected)
         .recover(handle_rejection)
         .with(cors)
@@ -95,13 +133,73 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     Ok(())
 }
 
-pub async fn protected_path(config: Config, path: FullPath) -> Result<Backend, warp::Rejection> {
-    let backend = config.backends.iter().find(|b| b.path == path.as_str());
+pub async fn protected_path(
+    config: Config,
+    host: Option<Authority>,
+    method: Method,
+    path: FullPath,
+) -> Result<Backend, warp::Rejection> {
+    let host = host.as_ref().map(|h| h.host());
+    routes::find(&config.backends, host, &method, path.as_str()).map_err(|e| match e {
+        RouteError::NotFound => warp::reject(),
+        RouteError::MethodNotAllowed(allowed) => warp::reject::custom(MethodNotAllowed(allowed)),
+    })
+}
 
-    match backend {
-        Some(backend) => Ok(backend.clone()),
-        None => Err(warp::reject()),
-    }
+/// Reload the configuration when the process receives SIGHUP, so
+/// backends and upstream credentials can be rotated without a restart.
+/// Current configuration is kept if the new one fails to load.
+fn reload_on_hangup(config: SharedConfig) -> Result<(), std::io::Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match Config::load() {
+                Ok(new_config) => {
+                    info!("Configuration reloaded: {:?}", new_config);
+                    *config.write().unwrap() = new_config;
+                }
+                Err(e) => error!(error=%e, "Unable to reload configuration, keeping current"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Export the token store into the `storage.export_path` archive when
+/// the process receives SIGUSR1. The sled database is locked by the
+/// running proxy, so `cli export` can't read it.
+fn export_on_signal(config: SharedConfig, store: db::Store) -> Result<(), std::io::Error> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            let config = config.read().unwrap().clone();
+            let path = PathBuf::from(&config.storage.export_path);
+            let exported = Archive::export(store.as_ref(), config.tokens.root_key.as_ref())
+                .and_then(|archive| {
+                    let summary = archive.verify()?;
+                    archive.save(&path)?;
+                    Ok(summary)
+                });
+            match exported {
+                Ok(summary) => info!(
+                    entries = summary.entries,
+                    settlements = summary.settlements,
+                    path = %path.display(),
+                    "Token store exported"
+                ),
+                Err(e) => error!(error=%e, "Unable to export token store"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Warp helper passing a snapshot of the current configuration
+/// into request handlers.
+pub fn with_config(
+    config: SharedConfig,
+) -> impl Filter<Extract = (Config,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || config.read().unwrap().clone())
 }
 
 /// Warp helper for cloning configration and db references
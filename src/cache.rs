@@ -0,0 +1,115 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use bitcoin_hashes::{sha256, Hash};
+use itertools::Itertools;
+use stretto::AsyncCache;
+use tracing::{debug, info};
+
+use crate::{
+    config::{Backend, CacheCharge},
+    lsat::MiliSats,
+    upstream::UpstreamResponse,
+};
+
+/// Number of counters stretto keeps per expected cache entry
+const COUNTERS_PER_ENTRY: usize = 10;
+
+/// Per-backend caches of paid upstream responses, keyed on the
+/// sha256 of the canonical request data. Only backends with
+/// a `cache` section in their config get one.
+#[derive(Clone, Default)]
+pub struct ResponseCache {
+    caches: Arc<HashMap<String, AsyncCache<Vec<u8>, UpstreamResponse>>>,
+}
+
+impl Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("backends", &self.caches.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl ResponseCache {
+    /// Set up caches for all the backends that have caching enabled
+    pub fn new(backends: &[Backend]) -> Result<Self, anyhow::Error> {
+        let mut caches = HashMap::new();
+        for backend in backends {
+            if let Some(conf) = &backend.cache {
+                info!(backend = backend.name, ttl = conf.ttl, "Enabling response cache");
+                let cache = AsyncCache::new(
+                    conf.max_entries * COUNTERS_PER_ENTRY,
+                    conf.max_size,
+                    tokio::spawn,
+                )
+                .map_err(|e| anyhow!("unable to create cache for {}: {}", backend.name, e))?;
+                caches.insert(backend.name.clone(), cache);
+            }
+        }
+        Ok(Self {
+            caches: Arc::new(caches),
+        })
+    }
+
+    /// Find a cached response for the request
+    pub fn get(&self, backend: &Backend, key: &sha256::Hash) -> Option<UpstreamResponse> {
+        let cache = self.caches.get(&backend.name)?;
+        let resp = cache.get(&cache_key(backend, key))?;
+        debug!(backend = backend.name, "Response cache hit");
+        Some(resp.value().clone())
+    }
+
+    /// Store the upstream response so identical requests
+    /// can be answered without calling the upstream
+    pub async fn insert(&self, backend: &Backend, key: &sha256::Hash, resp: UpstreamResponse) {
+        if let (Some(cache), Some(conf)) = (self.caches.get(&backend.name), &backend.cache) {
+            let cost = resp.body.len() as i64;
+            cache
+                .insert_with_ttl(
+                    cache_key(backend, key),
+                    resp,
+                    cost,
+                    Duration::from_secs(conf.ttl),
+                )
+                .await;
+        }
+    }
+}
+
+/// Requests to the same backend with a different method, path or path
+/// parameters get different upstream responses. Fields are length
+/// prefixed, so no two different requests can share the key.
+fn cache_key(backend: &Backend, key: &sha256::Hash) -> Vec<u8> {
+    let method = backend.method.as_ref().map(|m| m.as_str()).unwrap_or_default();
+    let upstream_method = backend.upstream_method.as_deref().unwrap_or_default();
+    let mut cache_key = key.into_inner().to_vec();
+    let fields = [
+        method,
+        upstream_method,
+        backend.request_path.as_str(),
+        backend.upstream.as_str(),
+    ];
+    let params = backend
+        .params
+        .iter()
+        .sorted()
+        .flat_map(|(name, value)| [name.as_str(), value.as_str()]);
+    for field in fields.into_iter().chain(params) {
+        cache_key.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        cache_key.extend_from_slice(field.as_bytes());
+    }
+    cache_key
+}
+
+/// Price of a call that is served from the cache
+pub fn hit_price(backend: &Backend) -> MiliSats {
+    match &backend.cache {
+        Some(conf) => match conf.charge {
+            CacheCharge::Full => backend.get_price(),
+            CacheCharge::Discounted => MiliSats(conf.hit_price_msat.unwrap_or(backend.price_msat)),
+            CacheCharge::Free => MiliSats(0),
+        },
+        None => backend.get_price(),
+    }
+}
@@ -1,35 +1,379 @@
-use serde::Deserialize;
-use std::{collections::HashMap, net::IpAddr};
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug},
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+use warp::http::Method;
 
-use crate::lsat::MiliSats;
+use crate::{db, lsat::MiliSats, routes::Params};
+
+/// Prefix of a secret reference to an environment variable
+const SECRET_ENV_PREFIX: &str = "env:";
+/// Prefix of a secret reference to a file
+const SECRET_FILE_PREFIX: &str = "file:";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub server: Server,
-    pub lnd: Lnd,
+    /// LND nodes, a single node can be configured without the list
+    #[serde(deserialize_with = "one_or_many")]
+    pub lnd: Vec<Lnd>,
     pub backends: Vec<Backend>,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub tokens: Tokens,
 }
 // https://github.com/mehcode/config-rs
 
+/// Configuration shared between the request handlers,
+/// swapped as a whole when the config gets reloaded.
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+impl Config {
+    /// Load the configuration from the `config` file and `APP_`
+    /// prefixed env variables, resolving all the secret references.
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let mut config: Config = ::config::Config::builder()
+            // Add in `./config.yaml`
+            .add_source(::config::File::with_name("config"))
+            // Add in settings from the environment (with a prefix of APP)
+            // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
+            .add_source(::config::Environment::with_prefix("APP"))
+            .build()
+            .context("problem building the config")?
+            .try_deserialize()
+            .context("problem deserializing config")?;
+
+        config.resolve_secrets()?;
+        config.name_nodes()?;
+
+        if config.tokens.persistence == Persistence::Lazy && config.tokens.root_key.is_none() {
+            bail!("lazy token persistence requires the tokens.root_key to be set");
+        }
+        // without the root key the secrets can be derived from the token
+        // ids, encrypting them at rest would protect nothing
+        if config.storage.encryption.is_some() && config.tokens.root_key.is_none() {
+            bail!("storage encryption requires the tokens.root_key to be set");
+        }
+        Ok(config)
+    }
+
+    /// Longest a paid token stays valid, across all the backends
+    pub fn max_token_ttl(&self) -> u64 {
+        self.backends
+            .iter()
+            .map(|b| b.token_ttl)
+            .max()
+            .unwrap_or_else(default_token_ttl)
+    }
+
+    /// Name the unnamed LND nodes after their host, names
+    /// are recorded with the tokens so they must be unique
+    fn name_nodes(&mut self) -> Result<(), anyhow::Error> {
+        if self.lnd.is_empty() {
+            bail!("at least one LND node needs to be configured");
+        }
+        let mut names = HashSet::new();
+        for node in self.lnd.iter_mut() {
+            if node.name.is_empty() {
+                node.name = node.host.clone();
+            }
+            if !names.insert(node.name.clone()) {
+                bail!("duplicate LND node name {}", node.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace secret references with their actual values
+    fn resolve_secrets(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(root_key) = self.tokens.root_key.as_mut() {
+            root_key.resolve().context("tokens root key")?;
+        }
+        if let Some(encryption) = self.storage.encryption.as_mut() {
+            for (key_id, key) in encryption.keys.iter_mut() {
+                key.resolve()
+                    .with_context(|| format!("storage encryption key {}", key_id))?;
+            }
+        }
+        for backend in self.backends.iter_mut() {
+            for header in backend.headers.iter_mut() {
+                header
+                    .value
+                    .resolve()
+                    .with_context(|| format!("backend {}, header {}", backend.name, header.name))?;
+            }
+            if let Some(identity) = backend.identity.as_mut() {
+                identity
+                    .secret
+                    .resolve()
+                    .with_context(|| format!("backend {}, identity secret", backend.name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Configuration value holding sensitive data, never printed out.
+/// Each word of the value can reference a secret kept outside
+/// of the config file, either `env:VAR_NAME` or `file:/path/to/secret`.
+#[derive(Deserialize, Clone, Default)]
+#[serde(from = "String")]
+pub struct Secret(String);
+
+impl Secret {
+    /// The actual (resolved) value
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Load all the referenced secrets in place
+    pub fn resolve(&mut self) -> Result<(), anyhow::Error> {
+        self.0 = self
+            .0
+            .split(' ')
+            .map(resolve_reference)
+            .collect::<Result<Vec<_>, _>>()?
+            .join(" ");
+        Ok(())
+    }
+}
+
+fn resolve_reference(word: &str) -> Result<String, anyhow::Error> {
+    if let Some(var) = word.strip_prefix(SECRET_ENV_PREFIX) {
+        return std::env::var(var).with_context(|| format!("missing env variable {}", var));
+    }
+    if let Some(path) = word.strip_prefix(SECRET_FILE_PREFIX) {
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read secret file {}", path))?;
+        return Ok(secret.trim().to_string());
+    }
+    Ok(word.to_string())
+}
+
+impl From<String> for Secret {
+    fn from(val: String) -> Self {
+        Self(val)
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+/// Header injected into the upstream request, in the `Name: value`
+/// format. The value is treated as a secret.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct Header {
+    pub name: String,
+    pub value: Secret,
+}
+
+impl TryFrom<String> for Header {
+    type Error = anyhow::Error;
+
+    fn try_from(val: String) -> Result<Self, Self::Error> {
+        let (name, value) = val
+            .split_once(':')
+            .ok_or_else(|| anyhow!("header should be in the 'Name: value' format"))?;
+        if name.trim().is_empty() {
+            bail!("header name can't be empty");
+        }
+        Ok(Self {
+            name: name.trim().to_string(),
+            value: Secret(value.trim().to_string()),
+        })
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
     pub host: IpAddr,
     pub port: u16,
 }
 
+/// How the minted tokens are handled
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Tokens {
+    #[serde(default)]
+    pub persistence: Persistence,
+    /// key the macaroon secrets are derived from, required with
+    /// `lazy` persistence so the secrets can be recovered
+    pub root_key: Option<Secret>,
+}
+
+/// When the token entry gets written to the store
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Persistence {
+    /// when the challenge is issued
+    #[default]
+    Eager,
+    /// when the paid token is redeemed for the first time,
+    /// minting challenges doesn't touch the store at all
+    Lazy,
+}
+
+/// Where the tokens and their quota are stored
+#[derive(Debug, Deserialize, Clone)]
+pub struct Storage {
+    #[serde(default)]
+    pub engine: StorageEngine,
+    /// database file, not used by the `memory` engine
+    #[serde(default = "default_storage_path")]
+    pub path: String,
+    /// seconds between the sweeps of expired entries
+    #[serde(default = "default_sweep_interval")]
+    pub sweep_interval: u64,
+    /// encryption of the stored macaroon secrets
+    pub encryption: Option<Encryption>,
+    /// archive the running proxy exports the store into on SIGUSR1
+    #[serde(default = "default_export_path")]
+    pub export_path: String,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            engine: StorageEngine::default(),
+            path: default_storage_path(),
+            sweep_interval: default_sweep_interval(),
+            encryption: None,
+            export_path: default_export_path(),
+        }
+    }
+}
+
+/// Key-encryption keys for the stored secrets, keys are
+/// hex-encoded 32 bytes. Retired keys stay in `keys` until
+/// all the secrets are re-wrapped with the current one.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Encryption {
+    /// id of the key new secrets get encrypted with
+    pub key_id: String,
+    pub keys: HashMap<String, Secret>,
+}
+
+fn default_storage_path() -> String {
+    db::DEFAULT_NAME.to_string()
+}
+
+fn default_sweep_interval() -> u64 {
+    60
+}
+
+fn default_export_path() -> String {
+    "lsat-proxy-export.json".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageEngine {
+    /// embedded sled database
+    #[default]
+    Sled,
+    /// SQLite database
+    Sqlite,
+    /// in-memory, nothing survives a restart
+    Memory,
+}
+
+impl FromStr for StorageEngine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sled" => Ok(StorageEngine::Sled),
+            "sqlite" => Ok(StorageEngine::Sqlite),
+            "memory" => Ok(StorageEngine::Memory),
+            _ => bail!("unknown storage engine: {}", s),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Lnd {
+    /// recorded with the issued tokens, the host when not set
+    #[serde(default)]
+    pub name: String,
+    /// nodes with lower priority are preferred for new invoices
+    #[serde(default)]
+    pub priority: u32,
     pub host: String,
     pub tls_path: String,
+    /// macaroon with the permissions listed by `cli lnd-permissions`
     pub mac_path: String,
+    /// start with a macaroon granting more than needed, e.g. the admin one
+    #[serde(default)]
+    pub allow_excess_permissions: bool,
+    /// calls made to the node at the same time, the rest waits
+    #[serde(default = "default_max_concurrent_calls")]
+    pub max_concurrent_calls: usize,
+    /// seconds to wait for the node to answer a call
+    #[serde(default = "default_call_timeout")]
+    pub call_timeout: u64,
+    /// check the node can receive the invoices before issuing them
+    pub liquidity: Option<Liquidity>,
+}
+
+/// Inbound liquidity checks of an LND node
+#[derive(Debug, Deserialize, Clone)]
+pub struct Liquidity {
+    /// seconds between the channel checks
+    #[serde(default = "default_liquidity_interval")]
+    pub interval: u64,
+    /// warn when the receivable amount drops below, in mili-sats
+    #[serde(default)]
+    pub warn_below_msat: u64,
+}
+
+fn default_liquidity_interval() -> u64 {
+    60
+}
+
+fn default_max_concurrent_calls() -> usize {
+    64
+}
+
+fn default_call_timeout() -> u64 {
+    10
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<Lnd>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Lnd),
+        Many(Vec<Lnd>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(node) => vec![node],
+        OneOrMany::Many(nodes) => nodes,
+    })
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Backend {
     pub name: String,
+    /// route pattern, see [`crate::routes::Pattern`]
     pub path: String,
+    /// only match requests for this host
+    pub host: Option<String>,
     pub upstream: String,
-    pub headers: Vec<String>,
+    pub headers: Vec<Header>,
+    #[serde(default)]
     pub body: String,
     // dest_protocol: String,
     pub pass_fields: HashMap<String, String>,
@@ -38,10 +382,258 @@ pub struct Backend {
     pub price_msat: u32,
     pub budget_multiple: Option<u32>,
     pub price_passthrough: bool, // ask the backend
+    /// seconds a paid token stays valid after its invoice was paid
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+    #[serde(default)]
     pub response_fields: String,
+    /// how the upstream response is shaped before passing it to the client
+    #[serde(default)]
+    pub response_mode: ResponseMode,
+    /// seconds to wait for the upstream response, body included
+    pub upstream_timeout: Option<u64>,
+    /// cache paid responses for identical requests
+    pub cache: Option<Cache>,
+    /// forward signed identity of the paying customer to the upstream
+    pub identity: Option<Identity>,
+    /// let the upstream report the charge for the call
+    pub upstream_charge: Option<UpstreamCharge>,
+    /// prices depending on the value of a path parameter,
+    /// param name -> param value -> price in mili-sats
+    #[serde(default)]
+    pub param_prices: HashMap<String, HashMap<String, u32>>,
+    /// allowed HTTP methods, any method is allowed when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// prices depending on the HTTP method, `price_msat` is
+    /// used for methods that are not listed
+    #[serde(default)]
+    pub method_prices: HashMap<String, u32>,
+    /// HTTP method of the upstream call, same as the
+    /// method of the incoming request when not set
+    pub upstream_method: Option<String>,
+    /// parameters of the challenge invoices
+    #[serde(default)]
+    pub invoice: InvoiceParams,
+    /// path parameters captured for the current request
+    #[serde(skip)]
+    pub params: Params,
+    /// path of the current request
+    #[serde(skip)]
+    pub request_path: String,
+    /// HTTP method of the current request
+    #[serde(skip)]
+    pub method: Option<Method>,
+}
+
+/// Charge reported by the upstream in a response header,
+/// debited instead of the fixed `price_msat`
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpstreamCharge {
+    /// response header with the charge in mili-sats
+    #[serde(default = "default_charge_header")]
+    pub header: String,
+    /// max charge for a single call
+    pub max_msat: u32,
+}
+
+fn default_charge_header() -> String {
+    "X-Lsat-Charge-Msat".to_string()
+}
+
+fn default_token_ttl() -> u64 {
+    60 * 60 * 24 * 7 // 1 week
+}
+
+/// Parameters of the invoices issued for a backend
+#[derive(Debug, Deserialize, Clone)]
+pub struct InvoiceParams {
+    /// invoice description, `{backend}`, `{calls}` and `{token_id}`
+    /// get replaced with the backend name, the number of calls
+    /// paid for and the hex token id
+    #[serde(default = "default_invoice_memo")]
+    pub memo: String,
+    /// commit to the memo with its sha256 instead of including it
+    #[serde(default)]
+    pub description_hash: bool,
+    /// seconds the invoice can be paid for
+    #[serde(default = "default_invoice_expiry")]
+    pub expiry: u64,
+    /// include route hints for the private channels of the node
+    #[serde(default)]
+    pub private: bool,
+    /// on-chain address the payer can fall back to
+    pub fallback_address: Option<String>,
+    /// serve the challenges from invoices created upfront
+    pub pool: Option<Pool>,
+}
+
+impl Default for InvoiceParams {
+    fn default() -> Self {
+        Self {
+            memo: default_invoice_memo(),
+            description_hash: false,
+            expiry: default_invoice_expiry(),
+            private: false,
+            fallback_address: None,
+            pool: None,
+        }
+    }
+}
+
+/// Invoices created upfront for a backend, at its standard price
+#[derive(Debug, Deserialize, Clone)]
+pub struct Pool {
+    /// number of invoices kept ready
+    pub size: usize,
+    /// refill the pool once it has fewer invoices, half of `size` when not set
+    pub refill_below: Option<usize>,
+    /// seconds an invoice needs to be valid for to be served,
+    /// half of the invoice expiry when not set
+    pub min_validity: Option<u64>,
+}
+
+impl Pool {
+    pub fn refill_below(&self) -> usize {
+        self.refill_below.unwrap_or(self.size / 2).min(self.size)
+    }
+
+    pub fn min_validity(&self, params: &InvoiceParams) -> u64 {
+        self.min_validity.unwrap_or(params.expiry / 2)
+    }
+}
+
+fn default_invoice_memo() -> String {
+    "LSAT payment".to_string()
+}
+
+fn default_invoice_expiry() -> u64 {
+    60 * 10 // 10 minutes
+}
+
+/// Settings for the identity headers injected into upstream requests
+#[derive(Debug, Deserialize, Clone)]
+pub struct Identity {
+    /// secret shared with the upstream, used to sign the headers
+    pub secret: Secret,
+    /// prefix of the injected header names
+    #[serde(default = "default_identity_prefix")]
+    pub header_prefix: String,
+}
+
+fn default_identity_prefix() -> String {
+    "X-Lsat-".to_string()
+}
+
+/// Response cache settings for a backend
+#[derive(Debug, Deserialize, Clone)]
+pub struct Cache {
+    /// seconds a cached response stays valid
+    pub ttl: u64,
+    /// max total size of cached response bodies, in bytes
+    pub max_size: i64,
+    /// expected number of cached responses
+    #[serde(default = "default_cache_entries")]
+    pub max_entries: usize,
+    /// how calls served from the cache are charged
+    #[serde(default)]
+    pub charge: CacheCharge,
+    /// price of a cache hit with the `discounted` charge
+    pub hit_price_msat: Option<u32>,
+}
+
+fn default_cache_entries() -> usize {
+    1000
+}
+
+/// Charging policy for calls served from the cache
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheCharge {
+    /// full `price_msat`
+    #[default]
+    Full,
+    /// `hit_price_msat`
+    Discounted,
+    /// no charge
+    Free,
+}
+
+/// Escape the value so it can be placed inside a JSON string
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::Value::from(value).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Shape of the response returned to the client
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseMode {
+    /// upstream response passed through as-is, with its content type and headers
+    Raw,
+    /// JSON value extracted with `response_fields`
+    Json,
+    /// string extracted with `response_fields`, returned as plain text
+    Text,
+    /// string extracted with `response_fields`, split into paragraphs
+    #[default]
+    Paragraphs,
 }
 
 impl Backend {
+    /// Check if the backend accepts requests with the method
+    pub fn allows(&self, method: &Method) -> bool {
+        self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+
+    /// Apply the request method, path and captured path parameters to
+    /// the backend: `method_prices` set the price, `{name}` placeholders
+    /// in `upstream` and `body` get replaced with the parameter values
+    /// and `param_prices` override the price.
+    pub fn bind(&self, method: &Method, path: &str, params: Params) -> Backend {
+        let mut backend = self.clone();
+        if let Some((_, price)) = self
+            .method_prices
+            .iter()
+            .find(|(m, _)| m.eq_ignore_ascii_case(method.as_str()))
+        {
+            backend.price_msat = *price;
+        }
+
+        for (name, value) in params.iter() {
+            let placeholder = format!("{{{}}}", name);
+            backend.upstream = backend.upstream.replace(&placeholder, value);
+            backend.body = backend.body.replace(&placeholder, &json_escape(value));
+
+            let price = self.param_prices.get(name).and_then(|prices| {
+                prices
+                    .iter()
+                    .find(|(v, _)| v.eq_ignore_ascii_case(value))
+                    .map(|(_, price)| *price)
+            });
+            if let Some(price) = price {
+                backend.price_msat = price;
+            }
+        }
+        backend.params = params;
+        backend.request_path = path.to_string();
+        backend.method = Some(method.clone());
+        backend
+    }
+
+    /// Invoice description for the token, see [`InvoiceParams::memo`]
+    pub fn invoice_memo(&self, token_id: &str) -> String {
+        self.invoice
+            .memo
+            .replace("{backend}", &self.name)
+            .replace("{calls}", &self.budget_multiple.unwrap_or(1).to_string())
+            .replace("{token_id}", token_id)
+    }
+
     pub fn amount_total(&self) -> MiliSats {
         MiliSats(self.price_msat * self.budget_multiple.unwrap_or(1))
     }
@@ -50,6 +642,26 @@ impl Backend {
     }
 }
 
-enum Constraints {
-    Timeout(u32),
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use serde_json::json;
+
+    use super::Backend;
+
+    /// Backend with only the required fields set, routing the `path`
+    /// to an upstream on localhost at 100 mili-sats per call
+    pub fn backend(name: &str, path: &str) -> Backend {
+        serde_json::from_value(json!({
+            "name": name,
+            "path": path,
+            "upstream": "http://localhost:8080",
+            "headers": [],
+            "pass_fields": {},
+            "capabilties": "",
+            "constraints": {},
+            "price_msat": 100,
+            "price_passthrough": false,
+        }))
+        .unwrap()
+    }
 }
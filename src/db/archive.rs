@@ -0,0 +1,233 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use bitcoin_hashes::{sha256, Hash};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::config::Secret;
+
+use super::{
+    now, schema::SCHEMA_VERSION, Entry, PaymentState, Settlement, StoredSecret, TokenStore,
+    ENTRY_PREFIX,
+};
+
+/// Version of the archive format, version 1 archives
+/// have no settlements nor settle indexes
+const ARCHIVE_VERSION: u32 = 2;
+
+/// Portable JSON snapshot of the token store. Entries are kept as
+/// stored, so sealed secrets stay encrypted with their original keys.
+/// Settlements and settle indexes are included, so tokens paid for
+/// keep working and the invoice streams resume where they left off.
+/// The root key itself is never exported, only its fingerprint so
+/// the archive can be matched with the config it belongs to.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Archive {
+    pub version: u32,
+    pub schema_version: u32,
+    /// unix timestamp of the export
+    pub created_at: u64,
+    pub root_key_fingerprint: Option<String>,
+    /// hex sha256 of the serialized entries, settlements and indexes
+    pub checksum: String,
+    entries: Vec<Entry>,
+    #[serde(default)]
+    settlements: Vec<Settlement>,
+    /// node name -> settle index of its invoice stream
+    #[serde(default)]
+    settle_indexes: BTreeMap<String, u64>,
+}
+
+/// Summary of a verified archive
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub entries: usize,
+    pub pending: usize,
+    pub paid: usize,
+    pub sealed: usize,
+    pub settlements: usize,
+    /// total quota left in mili-sats
+    pub quota: u64,
+}
+
+impl Archive {
+    /// Snapshot all the entries, settlements and settle indexes of the
+    /// store, entries are read from its engine so secrets stay sealed
+    pub fn export(
+        store: &dyn TokenStore,
+        root_key: Option<&Secret>,
+    ) -> Result<Self, anyhow::Error> {
+        let engine = store.engine();
+        let mut archive = Self {
+            version: ARCHIVE_VERSION,
+            schema_version: engine.schema_version()?,
+            created_at: now(),
+            root_key_fingerprint: root_key.map(fingerprint),
+            checksum: String::new(),
+            entries: engine.iter().collect::<Result<Vec<_>, _>>()?,
+            settlements: engine.settlements()?,
+            settle_indexes: engine.settle_indexes()?,
+        };
+        archive.checksum = archive.digest()?;
+        Ok(archive)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let data = fs::read(path).with_context(|| format!("unable to read {}", path.display()))?;
+        serde_json::from_slice(&data).context("malformed archive")
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(path, data).with_context(|| format!("unable to write {}", path.display()))
+    }
+
+    /// Check the archive is complete and readable by this version
+    pub fn verify(&self) -> Result<Summary, anyhow::Error> {
+        if self.version == 0 || self.version > ARCHIVE_VERSION {
+            bail!("unsupported archive version {}", self.version);
+        }
+        if self.schema_version > SCHEMA_VERSION {
+            bail!(
+                "archive schema version {} is newer than the supported {}",
+                self.schema_version,
+                SCHEMA_VERSION
+            );
+        }
+        if self.digest()? != self.checksum {
+            bail!("archive checksum mismatch");
+        }
+
+        let mut ids = HashSet::new();
+        let mut summary = Summary::default();
+        for entry in self.entries.iter() {
+            if !entry.id.starts_with(ENTRY_PREFIX) {
+                bail!("invalid entry id {}", entry.id);
+            }
+            if !ids.insert(entry.id()) {
+                bail!("duplicate entry {}", entry.id);
+            }
+            match entry.state {
+                PaymentState::Pending => summary.pending += 1,
+                PaymentState::Paid => summary.paid += 1,
+            }
+            if matches!(entry.secret, StoredSecret::Sealed { .. }) {
+                summary.sealed += 1;
+            }
+            summary.quota += u64::from(entry.quota.0);
+        }
+        summary.entries = self.entries.len();
+        summary.settlements = self.settlements.len();
+        Ok(summary)
+    }
+
+    /// Check the archive was exported with given root key,
+    /// archives without the fingerprint match any key
+    pub fn matches_root_key(&self, root_key: Option<&Secret>) -> bool {
+        match (&self.root_key_fingerprint, root_key) {
+            (Some(expected), Some(key)) => *expected == fingerprint(key),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
+    /// Write the verified entries, settlements and settle indexes
+    /// into an empty store, returns the number of imported entries
+    pub fn import(&self, store: &dyn TokenStore) -> Result<usize, anyhow::Error> {
+        self.verify()?;
+        if !store.list()?.is_empty() {
+            bail!("target store is not empty");
+        }
+        for entry in self.entries.iter() {
+            if !store.create(entry)? {
+                bail!("entry {} already exists", entry.id);
+            }
+        }
+        for settlement in self.settlements.iter() {
+            store.settle(settlement)?;
+        }
+        for (node, index) in self.settle_indexes.iter() {
+            store.set_settle_index(node, *index)?;
+        }
+        info!(
+            count = self.entries.len(),
+            settlements = self.settlements.len(),
+            "imported archive"
+        );
+        Ok(self.entries.len())
+    }
+
+    /// Checksum of the archived data, version 1
+    /// archives only had the entries checksummed
+    fn digest(&self) -> Result<String, anyhow::Error> {
+        let data = match self.version {
+            1 => serde_json::to_vec(&self.entries)?,
+            _ => serde_json::to_vec(&(&self.entries, &self.settlements, &self.settle_indexes))?,
+        };
+        Ok(sha256::Hash::hash(&data).to_string())
+    }
+}
+
+fn fingerprint(root_key: &Secret) -> String {
+    sha256::Hash::hash(root_key.expose().as_bytes()).to_string()[..16].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{fixtures, memory::MemoryStore};
+
+    fn store() -> MemoryStore {
+        let store = MemoryStore::default();
+        for quota in [100, 200] {
+            let entry = fixtures::paid(quota, now() + 60);
+            store.insert(&entry).unwrap();
+            store.settle(&fixtures::settlement(&entry, now())).unwrap();
+        }
+        store.set_settle_index("mock", 42).unwrap();
+        store
+    }
+
+    fn root_key() -> Secret {
+        Secret::from("root key".to_string())
+    }
+
+    #[test]
+    fn round_trips_entries_and_settlements() {
+        let archive = Archive::export(&store(), Some(&root_key())).unwrap();
+        let summary = archive.verify().unwrap();
+        assert_eq!((summary.entries, summary.paid), (2, 2));
+        assert_eq!((summary.settlements, summary.quota), (2, 300));
+        assert!(archive.matches_root_key(Some(&root_key())));
+        assert!(!archive.matches_root_key(None));
+
+        let target = MemoryStore::default();
+        assert_eq!(archive.import(&target).unwrap(), 2);
+        assert_eq!(target.settlements().unwrap().len(), 2);
+        assert_eq!(target.settle_index("mock").unwrap(), 42);
+        // importing twice would duplicate the entries
+        assert!(archive.import(&target).is_err());
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let mut archive = Archive::export(&store(), None).unwrap();
+        archive.settle_indexes.insert("mock".to_string(), 1);
+        assert!(archive.verify().is_err());
+    }
+
+    #[test]
+    fn reads_version_1_archives() {
+        let mut archive = Archive::export(&store(), None).unwrap();
+        archive.version = 1;
+        archive.settlements.clear();
+        archive.settle_indexes.clear();
+        archive.checksum = archive.digest().unwrap();
+        assert_eq!(archive.verify().unwrap().entries, 2);
+    }
+}
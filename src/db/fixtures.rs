@@ -0,0 +1,31 @@
+//! Entries and settlements the store tests start from
+
+use lightning::ln::PaymentHash;
+use macaroon::MacaroonKey;
+
+use super::{Entry, Settlement};
+use crate::lsat::{self, MiliSats};
+
+/// Pending entry of a new challenge with a random payment hash
+pub fn entry(quota: u32, expires_at: u64) -> Entry {
+    let id = lsat::Id::new(PaymentHash(rand::random()));
+    Entry::new(&id, &MacaroonKey::generate(b"secret"), MiliSats(quota), expires_at).unwrap()
+}
+
+/// Entry of a paid token with a random payment hash
+pub fn paid(quota: u32, expires_at: u64) -> Entry {
+    let id = lsat::Id::new(PaymentHash(rand::random()));
+    Entry::paid(&id, &MacaroonKey::generate(b"secret"), MiliSats(quota), expires_at).unwrap()
+}
+
+/// Settlement of the entry invoice for its quota, on the `mock` node
+pub fn settlement(entry: &Entry, settle_date: u64) -> Settlement {
+    Settlement {
+        payment_hash: entry.payment_hash.clone().unwrap(),
+        preimage: [1; 32],
+        value_msat: u64::from(entry.quota.0),
+        amt_paid_msat: u64::from(entry.quota.0),
+        settle_date,
+        node: "mock".to_string(),
+    }
+}
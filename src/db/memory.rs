@@ -0,0 +1,159 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use anyhow::anyhow;
+
+use crate::lsat::MiliSats;
+
+use super::{
+    debited, schema::SCHEMA_VERSION, Entry, PaymentState, Settlement, StoredSecret, TokenStore,
+};
+
+/// Token store keeping everything in memory, nothing survives
+/// a restart. Useful for tests and local development.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    settlements: Mutex<HashMap<String, Settlement>>,
+    settle_indexes: Mutex<HashMap<String, u64>>,
+}
+
+impl TokenStore for MemoryStore {
+    fn get(&self, id: &str) -> Result<Option<Entry>, anyhow::Error> {
+        Ok(self.entries.lock().unwrap().get(id).cloned())
+    }
+
+    fn insert(&self, entry: &Entry) -> Result<(), anyhow::Error> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.id().to_string(), entry.clone());
+        Ok(())
+    }
+
+    fn create(&self, entry: &Entry) -> Result<bool, anyhow::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(entry.id()) {
+            return Ok(false);
+        }
+        entries.insert(entry.id().to_string(), entry.clone());
+        Ok(true)
+    }
+
+    fn debit(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get_mut(id)
+            .ok_or_else(|| anyhow!("no entry for {}", id))?;
+        entry.quota = debited(entry, amount)?;
+        Ok(entry.quota.clone())
+    }
+
+    fn refund(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get_mut(id)
+            .ok_or_else(|| anyhow!("no entry for {}", id))?;
+        entry.quota = MiliSats(entry.quota.0 + amount.0);
+        Ok(entry.quota.clone())
+    }
+
+    fn set_secret(&self, id: &str, secret: &StoredSecret) -> Result<bool, anyhow::Error> {
+        Ok(match self.entries.lock().unwrap().get_mut(id) {
+            Some(entry) => {
+                entry.secret = secret.clone();
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn mark_paid(&self, id: &str, expires_at: u64) -> Result<(), anyhow::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get_mut(id)
+            .ok_or_else(|| anyhow!("no entry for {}", id))?;
+        entry.state = PaymentState::Paid;
+        entry.expires_at = Some(expires_at);
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.entries.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self.entries.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry, anyhow::Error>> + '_> {
+        let entries: Vec<_> = self.entries.lock().unwrap().values().cloned().collect();
+        Box::new(entries.into_iter().map(Ok))
+    }
+
+    fn settle(&self, settlement: &Settlement) -> Result<(), anyhow::Error> {
+        self.settlements
+            .lock()
+            .unwrap()
+            .insert(settlement.payment_hash.clone(), settlement.clone());
+        Ok(())
+    }
+
+    fn settlement(&self, payment_hash: &str) -> Result<Option<Settlement>, anyhow::Error> {
+        Ok(self.settlements.lock().unwrap().get(payment_hash).cloned())
+    }
+
+    fn settlements(&self) -> Result<Vec<Settlement>, anyhow::Error> {
+        Ok(self.settlements.lock().unwrap().values().cloned().collect())
+    }
+
+    fn prune_settlements(&self, settled_before: u64) -> Result<u64, anyhow::Error> {
+        let mut settlements = self.settlements.lock().unwrap();
+        let before = settlements.len();
+        settlements.retain(|_, s| s.settle_date >= settled_before);
+        Ok((before - settlements.len()) as u64)
+    }
+
+    fn settle_index(&self, node: &str) -> Result<u64, anyhow::Error> {
+        Ok(self
+            .settle_indexes
+            .lock()
+            .unwrap()
+            .get(node)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    fn set_settle_index(&self, node: &str, index: u64) -> Result<(), anyhow::Error> {
+        self.settle_indexes
+            .lock()
+            .unwrap()
+            .insert(node.to_string(), index);
+        Ok(())
+    }
+
+    fn settle_indexes(&self) -> Result<BTreeMap<String, u64>, anyhow::Error> {
+        Ok(self
+            .settle_indexes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(node, index)| (node.clone(), *index))
+            .collect())
+    }
+
+    fn schema_version(&self) -> Result<u32, anyhow::Error> {
+        Ok(SCHEMA_VERSION)
+    }
+
+    fn migrate(&self, _version: u32) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    fn engine(&self) -> &dyn TokenStore {
+        self
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use hex::ToHex;
+use macaroon::MacaroonKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config,
+    lsat::{self, MiliSats, ToSha256},
+};
+
+pub mod archive;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod memory;
+pub mod schema;
+pub mod sealed;
+pub mod sled;
+pub mod sqlite;
+pub mod sweeper;
+
+pub static DEFAULT_NAME: &str = "lsat-proxy.db";
+
+/// Prefix of the token entry ids
+pub static ENTRY_PREFIX: &str = "lsat/proxy/secrets/";
+
+/// Token store shared between the request handlers
+pub type Store = Arc<dyn TokenStore>;
+
+/// Payment state of the token
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentState {
+    /// challenge was issued, invoice not paid yet
+    #[default]
+    Pending,
+    /// invoice paid and the token was redeemed
+    Paid,
+}
+
+impl PaymentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentState::Pending => "pending",
+            PaymentState::Paid => "paid",
+        }
+    }
+}
+
+impl FromStr for PaymentState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(PaymentState::Pending),
+            "paid" => Ok(PaymentState::Paid),
+            _ => bail!("unknown payment state: {}", s),
+        }
+    }
+}
+
+/// Macaroon secret as kept in the store
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum StoredSecret {
+    /// secret in plaintext
+    Plain([u8; 32]),
+    /// secret encrypted with the key-encryption key `key_id`
+    Sealed {
+        key_id: String,
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    id: String,
+    secret: StoredSecret,
+    pub quota: MiliSats,
+    /// unix timestamp of the challenge
+    #[serde(default)]
+    pub created_at: u64,
+    /// unix timestamp after which the entry gets removed, the invoice
+    /// expiry while pending and the token expiry once paid
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub state: PaymentState,
+    /// hex encoded payment hash of the challenge invoice
+    #[serde(default)]
+    pub payment_hash: Option<String>,
+    /// name of the LND node that issued the invoice
+    #[serde(default)]
+    pub node: Option<String>,
+}
+
+impl Entry {
+    pub fn new(
+        id: &lsat::Id,
+        secret: &MacaroonKey,
+        quota: MiliSats,
+        expires_at: u64,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            id: Self::key(id)?,
+            secret: StoredSecret::Plain(*secret.as_ref()),
+            quota,
+            created_at: now(),
+            expires_at: Some(expires_at),
+            state: PaymentState::Pending,
+            payment_hash: Some(hex::encode(id.payment_hash.0)),
+            node: None,
+        })
+    }
+
+    /// Id of the store entry for the LSAT id
+    pub fn key(id: &lsat::Id) -> Result<String, anyhow::Error> {
+        Ok(format!(
+            "{}{}",
+            ENTRY_PREFIX,
+            id.to_sha256()?.encode_hex::<String>()
+        ))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn secret(&self) -> Result<MacaroonKey, anyhow::Error> {
+        match &self.secret {
+            StoredSecret::Plain(secret) => Ok(MacaroonKey::from(*secret)),
+            StoredSecret::Sealed { key_id, .. } => {
+                bail!("secret is encrypted with key {}", key_id)
+            }
+        }
+    }
+
+    /// Entry for an already paid token, valid until `expires_at`
+    pub fn paid(
+        id: &lsat::Id,
+        secret: &MacaroonKey,
+        quota: MiliSats,
+        expires_at: u64,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            state: PaymentState::Paid,
+            ..Self::new(id, secret, quota, expires_at)?
+        })
+    }
+
+    /// Check if the entry is past its expiry
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(ts) if ts < now)
+    }
+}
+
+/// Settled invoice, recorded as the invoice updates stream in
+/// so paid tokens can be verified without asking LND
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Settlement {
+    /// hex encoded payment hash
+    pub payment_hash: String,
+    pub preimage: [u8; 32],
+    pub value_msat: u64,
+    pub amt_paid_msat: u64,
+    /// unix timestamp of the settlement
+    pub settle_date: u64,
+    /// name of the LND node the invoice was settled on
+    #[serde(default)]
+    pub node: String,
+}
+
+/// Storage of the minted tokens, their secrets and remaining quota
+pub trait TokenStore: Send + Sync {
+    /// Find the entry with given id
+    fn get(&self, id: &str) -> Result<Option<Entry>, anyhow::Error>;
+
+    /// Insert a new entry or replace the existing one
+    fn insert(&self, entry: &Entry) -> Result<(), anyhow::Error>;
+
+    /// Insert the entry only if there's no entry with the same id yet,
+    /// returns false when the entry already existed
+    fn create(&self, entry: &Entry) -> Result<bool, anyhow::Error>;
+
+    /// Atomically subtract the amount from the entry quota and return
+    /// the quota left. Used up entries are kept until they expire, so
+    /// lazily persisted tokens can't be redeemed again.
+    fn debit(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error>;
+
+    /// Atomically add the amount back to the entry quota, e.g. the part
+    /// of a reserved price that wasn't spent, and return the quota left
+    fn refund(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error>;
+
+    /// Replace only the secret of the entry, leaving its quota and state
+    /// untouched, returns false when there's no entry with the id
+    fn set_secret(&self, id: &str, secret: &StoredSecret) -> Result<bool, anyhow::Error>;
+
+    /// Mark the entry as paid, valid until the given timestamp
+    fn mark_paid(&self, id: &str, expires_at: u64) -> Result<(), anyhow::Error>;
+
+    /// Remove the entry with given id
+    fn remove(&self, id: &str) -> Result<(), anyhow::Error>;
+
+    /// Ids of all the stored entries
+    fn list(&self) -> Result<Vec<String>, anyhow::Error>;
+
+    /// Iterate over all the stored entries
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry, anyhow::Error>> + '_>;
+
+    /// Record the settled invoice
+    fn settle(&self, settlement: &Settlement) -> Result<(), anyhow::Error>;
+
+    /// Find the settlement of the invoice with given hex payment hash
+    fn settlement(&self, payment_hash: &str) -> Result<Option<Settlement>, anyhow::Error>;
+
+    /// All the recorded settlements
+    fn settlements(&self) -> Result<Vec<Settlement>, anyhow::Error>;
+
+    /// Remove the settlements settled before the timestamp,
+    /// returns the number of removed settlements
+    fn prune_settlements(&self, settled_before: u64) -> Result<u64, anyhow::Error>;
+
+    /// Settle index of the last invoice update recorded from the invoice
+    /// stream of the LND `node`, 0 when nothing was recorded yet
+    fn settle_index(&self, node: &str) -> Result<u64, anyhow::Error>;
+
+    /// Remember the settle index the invoice stream of the `node` resumes from
+    fn set_settle_index(&self, node: &str, index: u64) -> Result<(), anyhow::Error>;
+
+    /// Settle indexes of all the nodes, node name -> index
+    fn settle_indexes(&self) -> Result<BTreeMap<String, u64>, anyhow::Error>;
+
+    /// Version of the schema the stored data is in
+    fn schema_version(&self) -> Result<u32, anyhow::Error>;
+
+    /// Upgrade the stored data to the schema `version`
+    /// from the version right before it
+    fn migrate(&self, version: u32) -> Result<(), anyhow::Error>;
+
+    /// Storage engine the data ends up in, entries read from it
+    /// have their secrets as stored (e.g. still encrypted)
+    fn engine(&self) -> &dyn TokenStore;
+}
+
+/// Open the token store configured in the `storage` section and
+/// migrate it to the current schema, secrets get encrypted when
+/// the `encryption` is configured
+pub fn open(conf: &config::Storage) -> Result<Store, anyhow::Error> {
+    let store = open_engine(conf)?;
+    schema::run(store.as_ref())?;
+    Ok(match &conf.encryption {
+        Some(encryption) => Arc::new(sealed::SealedStore::new(
+            store,
+            sealed::Keyring::from_config(encryption)?,
+        )),
+        None => Arc::from(store),
+    })
+}
+
+/// Open the storage engine itself, without the encryption layer
+pub fn open_engine(conf: &config::Storage) -> Result<Box<dyn TokenStore>, anyhow::Error> {
+    Ok(match conf.engine {
+        config::StorageEngine::Sled => Box::new(sled::SledStore::open(&conf.path)?),
+        config::StorageEngine::Sqlite => Box::new(sqlite::SqliteStore::open(&conf.path)?),
+        config::StorageEngine::Memory => Box::new(memory::MemoryStore::default()),
+    })
+}
+
+/// Current unix timestamp
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Quota left after debiting the amount, fails
+/// when there's not enough budget left
+pub(crate) fn debited(entry: &Entry, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+    if entry.quota.0 < amount.0 {
+        bail!(
+            "not enough quota left: {} < {}",
+            entry.quota.0,
+            amount.0
+        );
+    }
+    Ok(MiliSats(entry.quota.0 - amount.0))
+}
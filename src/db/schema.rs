@@ -0,0 +1,100 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{Entry, TokenStore};
+
+/// Version of the storage schema this build reads and writes
+pub const SCHEMA_VERSION: u32 = 6;
+
+/// Validity given to the entries written without an expiry, the
+/// default `token_ttl` counted from the migration
+pub(crate) const UNVERSIONED_TTL: u64 = 60 * 60 * 24 * 7;
+
+/// Step upgrading the stored data to `version` from the one before
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+}
+
+/// All the schema changes, the engines implement each of them
+/// in `TokenStore::migrate`. Version 1 is the unversioned schema.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "tag the entry records with their version, add the sealed secret columns",
+    },
+    Migration {
+        version: 3,
+        description: "add the invoice settlement index",
+    },
+    Migration {
+        version: 4,
+        description: "record the payment hash of the entries, add the invoice stream cursor",
+    },
+    Migration {
+        version: 5,
+        description: "record the LND node of the entries and settlements",
+    },
+    Migration {
+        version: 6,
+        description: "give the entries without an expiry one, so they get swept",
+    },
+];
+
+/// Entry record as serialized by the key-value engines, tagged with
+/// the version it was written in. Older variants get converted into
+/// the current `Entry` when read. Version 1 records were untagged.
+#[derive(Deserialize, Serialize)]
+pub(crate) enum Record {
+    V2(Entry),
+}
+
+impl Record {
+    pub(crate) fn encode(entry: &Entry) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(rmp_serde::to_vec_named(&Record::V2(entry.clone()))?)
+    }
+
+    pub(crate) fn decode(value: &[u8]) -> Result<Entry, anyhow::Error> {
+        match rmp_serde::from_slice(value).context("unknown entry record")? {
+            Record::V2(entry) => Ok(entry),
+        }
+    }
+
+    /// Decode the untagged version 1 record, the fields
+    /// missing in it get their defaults
+    pub(crate) fn decode_v1(value: &[u8]) -> Result<Entry, anyhow::Error> {
+        Ok(rmp_serde::from_slice(value)?)
+    }
+}
+
+/// Migrations not applied to the store yet
+pub fn pending(store: &dyn TokenStore) -> Result<Vec<&'static Migration>, anyhow::Error> {
+    let version = store.schema_version()?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "store schema version {} is newer than the supported {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Bring the store up to the current schema version,
+/// returns the number of applied migrations
+pub fn run(store: &dyn TokenStore) -> Result<usize, anyhow::Error> {
+    let pending = pending(store)?;
+    for migration in pending.iter() {
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "migrating store"
+        );
+        store
+            .migrate(migration.version)
+            .with_context(|| format!("migration to version {} failed", migration.version))?;
+    }
+    Ok(pending.len())
+}
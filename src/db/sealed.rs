@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Context};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::Rng;
+use tracing::info;
+
+use crate::{config, lsat::MiliSats};
+
+use super::{Entry, Settlement, StoredSecret, TokenStore};
+
+/// Key-encryption keys (KEKs) used to encrypt the macaroon secrets at
+/// rest. New secrets are sealed with the current key, the older keys
+/// are kept around so existing entries can still be read.
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl Keyring {
+    pub fn from_config(conf: &config::Encryption) -> Result<Self, anyhow::Error> {
+        let mut keys = HashMap::new();
+        for (key_id, key) in conf.keys.iter() {
+            let key: [u8; 32] = hex::decode(key.expose())
+                .ok()
+                .and_then(|k| k.try_into().ok())
+                .ok_or_else(|| anyhow!("key {} should be 32 hex-encoded bytes", key_id))?;
+            keys.insert(key_id.to_string(), key);
+        }
+        if !keys.contains_key(&conf.key_id) {
+            bail!("current encryption key {} not found in keys", conf.key_id);
+        }
+        Ok(Self {
+            current: conf.key_id.clone(),
+            keys,
+        })
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<ChaCha20Poly1305, anyhow::Error> {
+        let key = self
+            .keys
+            .get(key_id)
+            .with_context(|| format!("unknown encryption key {}", key_id))?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    /// Encrypt the secret with the current key. Ciphertext is bound
+    /// to the entry id, so it can't be moved to another entry.
+    fn seal(&self, entry_id: &str, secret: &[u8; 32]) -> Result<StoredSecret, anyhow::Error> {
+        let nonce: [u8; 12] = rand::thread_rng().gen();
+        let ciphertext = self
+            .cipher(&self.current)?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: secret,
+                    aad: &aad(&self.current, entry_id),
+                },
+            )
+            .map_err(|_| anyhow!("failed to encrypt secret of {}", entry_id))?;
+        Ok(StoredSecret::Sealed {
+            key_id: self.current.clone(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn unseal(&self, entry_id: &str, secret: &StoredSecret) -> Result<[u8; 32], anyhow::Error> {
+        match secret {
+            StoredSecret::Plain(secret) => Ok(*secret),
+            StoredSecret::Sealed {
+                key_id,
+                nonce,
+                ciphertext,
+            } => self
+                .cipher(key_id)?
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: &aad(key_id, entry_id),
+                    },
+                )
+                .ok()
+                .and_then(|secret| secret.try_into().ok())
+                .ok_or_else(|| anyhow!("failed to decrypt secret of {}", entry_id)),
+        }
+    }
+
+    /// Check if the secret should be sealed again with the current key
+    fn is_stale(&self, secret: &StoredSecret) -> bool {
+        match secret {
+            StoredSecret::Plain(_) => true,
+            StoredSecret::Sealed { key_id, .. } => *key_id != self.current,
+        }
+    }
+}
+
+fn aad(key_id: &str, entry_id: &str) -> Vec<u8> {
+    [key_id.as_bytes(), b"/", entry_id.as_bytes()].concat()
+}
+
+/// Token store wrapper encrypting the macaroon secrets before they
+/// get to the underlying store and decrypting them when read back.
+pub struct SealedStore {
+    inner: Box<dyn TokenStore>,
+    keyring: Keyring,
+}
+
+impl SealedStore {
+    pub fn new(inner: Box<dyn TokenStore>, keyring: Keyring) -> Self {
+        Self { inner, keyring }
+    }
+
+    fn seal(&self, entry: &Entry) -> Result<Entry, anyhow::Error> {
+        let secret = self.keyring.unseal(&entry.id, &entry.secret)?;
+        Ok(Entry {
+            secret: self.keyring.seal(&entry.id, &secret)?,
+            ..entry.clone()
+        })
+    }
+
+    fn unseal(&self, entry: Entry) -> Result<Entry, anyhow::Error> {
+        let secret = self.keyring.unseal(&entry.id, &entry.secret)?;
+        Ok(Entry {
+            secret: StoredSecret::Plain(secret),
+            ..entry
+        })
+    }
+
+    /// Encrypt all the secrets with the current key, including the ones
+    /// stored in plaintext. Returns the number of re-wrapped entries.
+    /// Only the secrets are written, so quota debited in the meantime
+    /// by a running proxy isn't overwritten.
+    pub fn rewrap(&self) -> Result<usize, anyhow::Error> {
+        let mut count = 0;
+        for entry in self.inner.iter() {
+            let entry = entry?;
+            if self.keyring.is_stale(&entry.secret)
+                && self
+                    .inner
+                    .set_secret(&entry.id, &self.seal(&entry)?.secret)?
+            {
+                count += 1;
+            }
+        }
+        info!(count, key_id = self.keyring.current, "re-wrapped secrets");
+        Ok(count)
+    }
+}
+
+impl TokenStore for SealedStore {
+    fn get(&self, id: &str) -> Result<Option<Entry>, anyhow::Error> {
+        self.inner.get(id)?.map(|e| self.unseal(e)).transpose()
+    }
+
+    fn insert(&self, entry: &Entry) -> Result<(), anyhow::Error> {
+        self.inner.insert(&self.seal(entry)?)
+    }
+
+    fn create(&self, entry: &Entry) -> Result<bool, anyhow::Error> {
+        self.inner.create(&self.seal(entry)?)
+    }
+
+    fn debit(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        self.inner.debit(id, amount)
+    }
+
+    fn refund(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        self.inner.refund(id, amount)
+    }
+
+    fn set_secret(&self, id: &str, secret: &StoredSecret) -> Result<bool, anyhow::Error> {
+        let secret = self.keyring.unseal(id, secret)?;
+        self.inner.set_secret(id, &self.keyring.seal(id, &secret)?)
+    }
+
+    fn mark_paid(&self, id: &str, expires_at: u64) -> Result<(), anyhow::Error> {
+        self.inner.mark_paid(id, expires_at)
+    }
+
+    fn remove(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.inner.remove(id)
+    }
+
+    fn list(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.inner.list()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry, anyhow::Error>> + '_> {
+        Box::new(self.inner.iter().map(|e| self.unseal(e?)))
+    }
+
+    fn settle(&self, settlement: &Settlement) -> Result<(), anyhow::Error> {
+        self.inner.settle(settlement)
+    }
+
+    fn settlement(&self, payment_hash: &str) -> Result<Option<Settlement>, anyhow::Error> {
+        self.inner.settlement(payment_hash)
+    }
+
+    fn settlements(&self) -> Result<Vec<Settlement>, anyhow::Error> {
+        self.inner.settlements()
+    }
+
+    fn prune_settlements(&self, settled_before: u64) -> Result<u64, anyhow::Error> {
+        self.inner.prune_settlements(settled_before)
+    }
+
+    fn settle_index(&self, node: &str) -> Result<u64, anyhow::Error> {
+        self.inner.settle_index(node)
+    }
+
+    fn set_settle_index(&self, node: &str, index: u64) -> Result<(), anyhow::Error> {
+        self.inner.set_settle_index(node, index)
+    }
+
+    fn settle_indexes(&self) -> Result<BTreeMap<String, u64>, anyhow::Error> {
+        self.inner.settle_indexes()
+    }
+
+    fn schema_version(&self) -> Result<u32, anyhow::Error> {
+        self.inner.schema_version()
+    }
+
+    fn migrate(&self, version: u32) -> Result<(), anyhow::Error> {
+        self.inner.migrate(version)
+    }
+
+    fn engine(&self) -> &dyn TokenStore {
+        self.inner.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Secret,
+        db::{fixtures, memory::MemoryStore},
+        lsat::MiliSats,
+    };
+
+    fn sealed_store(current: &str) -> SealedStore {
+        let keys = [("old", [1; 32]), ("new", [2; 32])]
+            .iter()
+            .map(|(id, key)| (id.to_string(), Secret::from(hex::encode(key))))
+            .collect();
+        let keyring = Keyring::from_config(&config::Encryption {
+            key_id: current.to_string(),
+            keys,
+        })
+        .unwrap();
+        SealedStore::new(Box::new(MemoryStore::default()), keyring)
+    }
+
+    /// Key the stored secret of the entry is sealed with
+    fn sealed_with(store: &SealedStore, id: &str) -> Option<String> {
+        match store.inner.get(id).unwrap().unwrap().secret {
+            StoredSecret::Sealed { key_id, .. } => Some(key_id),
+            StoredSecret::Plain(_) => None,
+        }
+    }
+
+    #[test]
+    fn secrets_are_sealed_at_rest() {
+        let store = sealed_store("old");
+        let entry = fixtures::paid(100, 0);
+        store.insert(&entry).unwrap();
+
+        assert_eq!(sealed_with(&store, entry.id()).as_deref(), Some("old"));
+        assert_eq!(store.get(entry.id()).unwrap().unwrap().secret, entry.secret);
+    }
+
+    #[test]
+    fn rewrap_keeps_the_quota() {
+        let (plain, sealed) = (fixtures::paid(100, 0), fixtures::paid(100, 0));
+        let old = sealed_store("old");
+        old.insert(&sealed).unwrap();
+
+        let store = sealed_store("new");
+        store.inner.insert(&plain).unwrap();
+        let stored = old.inner.get(sealed.id()).unwrap().unwrap();
+        store.inner.insert(&stored).unwrap();
+        store.debit(plain.id(), &MiliSats(40)).unwrap();
+
+        assert_eq!(store.rewrap().unwrap(), 2);
+        assert_eq!(store.rewrap().unwrap(), 0);
+        for entry in [&plain, &sealed] {
+            assert_eq!(sealed_with(&store, entry.id()).as_deref(), Some("new"));
+            assert_eq!(store.get(entry.id()).unwrap().unwrap().secret, entry.secret);
+        }
+        assert_eq!(store.get(plain.id()).unwrap().unwrap().quota, MiliSats(60));
+    }
+}
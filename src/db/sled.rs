@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context};
+use tracing::{debug, info};
+
+use crate::lsat::MiliSats;
+
+use super::{
+    debited, now,
+    schema::{Record, SCHEMA_VERSION, UNVERSIONED_TTL},
+    Entry, PaymentState, Settlement, StoredSecret, TokenStore, ENTRY_PREFIX,
+};
+
+/// Prefix of the settle index keys the invoice streams resume from,
+/// followed by the node name
+static SETTLE_INDEX_PREFIX: &str = "lsat/proxy/settle_index/";
+
+/// Prefix of the settlement keys, followed by the hex payment hash
+static SETTLEMENT_PREFIX: &str = "lsat/proxy/settlements/";
+
+/// Key of the schema version marker, unversioned databases are version 1
+static SCHEMA_VERSION_KEY: &str = "lsat/proxy/schema_version";
+
+/// Token store backed by an embedded sled database
+pub struct SledStore {
+    db: ::sled::Db,
+}
+
+impl SledStore {
+    pub fn open(path: &str) -> Result<Self, anyhow::Error> {
+        info!(path, "opening sled database");
+        Self::init(::sled::open(path).context("failed to open sled database")?)
+    }
+
+    /// Set up the store over the opened database
+    fn init(db: ::sled::Db) -> Result<Self, anyhow::Error> {
+        let store = Self { db };
+        // new databases start at the current schema
+        if !store.db.contains_key(SCHEMA_VERSION_KEY)?
+            && store.db.scan_prefix(ENTRY_PREFIX).next().is_none()
+        {
+            store.set_schema_version(SCHEMA_VERSION)?;
+        }
+        Ok(store)
+    }
+
+    fn set_schema_version(&self, version: u32) -> Result<(), anyhow::Error> {
+        self.db.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Tag all the version 1 records, records already
+    /// tagged are skipped so it can be resumed
+    fn migrate_v2(&self) -> Result<(), anyhow::Error> {
+        for item in self.db.scan_prefix(ENTRY_PREFIX) {
+            let (key, value) = item?;
+            if Record::decode(&value).is_ok() {
+                continue;
+            }
+            let entry = Record::decode_v1(&value).with_context(|| {
+                format!("unreadable entry {}", String::from_utf8_lossy(&key))
+            })?;
+            self.db.insert(key, Record::encode(&entry)?)?;
+        }
+        Ok(())
+    }
+
+    /// Give the entries written without an expiry one, so they get swept
+    fn migrate_v6(&self) -> Result<(), anyhow::Error> {
+        let expires_at = now() + UNVERSIONED_TTL;
+        for id in self.list()? {
+            self.update(&id, |entry| {
+                entry.expires_at.get_or_insert(expires_at);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Atomically modify the entry, retrying until
+    /// nobody else changed it in the meantime
+    fn update<F>(&self, id: &str, modify: F) -> Result<Entry, anyhow::Error>
+    where
+        F: Fn(&mut Entry) -> Result<(), anyhow::Error>,
+    {
+        loop {
+            let current = match self.db.get(id)? {
+                Some(current) => current,
+                None => bail!("no entry in db for {}", id),
+            };
+            let mut entry = Record::decode(&current)?;
+
+            modify(&mut entry)?;
+
+            let new = Some(Record::encode(&entry)?);
+            if self.db.compare_and_swap(id, Some(current), new)?.is_ok() {
+                return Ok(entry);
+            }
+        }
+    }
+}
+
+impl TokenStore for SledStore {
+    fn get(&self, id: &str) -> Result<Option<Entry>, anyhow::Error> {
+        let entry = self.db.get(id).context("failed interact with db")?;
+        debug!(id, "Got entry from db: {:?}", entry);
+        Ok(match entry {
+            Some(entry) => Some(Record::decode(&entry)?),
+            None => None,
+        })
+    }
+
+    fn insert(&self, entry: &Entry) -> Result<(), anyhow::Error> {
+        info!(id = entry.id(), "inserting into db");
+        let value = Record::encode(entry)?;
+        self.db.insert(entry.id(), value)?;
+        Ok(())
+    }
+
+    fn create(&self, entry: &Entry) -> Result<bool, anyhow::Error> {
+        let value = Record::encode(entry)?;
+        let created = self
+            .db
+            .compare_and_swap(entry.id(), None::<Vec<u8>>, Some(value))?
+            .is_ok();
+        info!(id = entry.id(), created, "creating in db");
+        Ok(created)
+    }
+
+    fn debit(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        let entry = self.update(id, |entry| {
+            entry.quota = debited(entry, amount)?;
+            Ok(())
+        })?;
+        info!(id, quota = entry.quota.0, "debited in db");
+        Ok(entry.quota)
+    }
+
+    fn refund(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        let entry = self.update(id, |entry| {
+            entry.quota = MiliSats(entry.quota.0 + amount.0);
+            Ok(())
+        })?;
+        info!(id, quota = entry.quota.0, "refunded in db");
+        Ok(entry.quota)
+    }
+
+    fn set_secret(&self, id: &str, secret: &StoredSecret) -> Result<bool, anyhow::Error> {
+        if self.db.get(id)?.is_none() {
+            return Ok(false);
+        }
+        self.update(id, |entry| {
+            entry.secret = secret.clone();
+            Ok(())
+        })?;
+        Ok(true)
+    }
+
+    fn mark_paid(&self, id: &str, expires_at: u64) -> Result<(), anyhow::Error> {
+        self.update(id, |entry| {
+            entry.state = PaymentState::Paid;
+            entry.expires_at = Some(expires_at);
+            Ok(())
+        })?;
+        info!(id, expires_at, "marked as paid in db");
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), anyhow::Error> {
+        info!(id, "removing from db");
+        self.db.remove(id)?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.db
+            .scan_prefix(ENTRY_PREFIX)
+            .keys()
+            .map(|k| Ok(String::from_utf8(k?.to_vec())?))
+            .collect()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry, anyhow::Error>> + '_> {
+        Box::new(
+            self.db
+                .scan_prefix(ENTRY_PREFIX)
+                .values()
+                .map(|v| Record::decode(&v?)),
+        )
+    }
+
+    fn settle(&self, settlement: &Settlement) -> Result<(), anyhow::Error> {
+        let key = format!("{}{}", SETTLEMENT_PREFIX, settlement.payment_hash);
+        self.db.insert(key, rmp_serde::to_vec_named(settlement)?)?;
+        Ok(())
+    }
+
+    fn settlement(&self, payment_hash: &str) -> Result<Option<Settlement>, anyhow::Error> {
+        let key = format!("{}{}", SETTLEMENT_PREFIX, payment_hash);
+        Ok(match self.db.get(key)? {
+            Some(settlement) => Some(rmp_serde::from_slice(&settlement)?),
+            None => None,
+        })
+    }
+
+    fn settlements(&self) -> Result<Vec<Settlement>, anyhow::Error> {
+        self.db
+            .scan_prefix(SETTLEMENT_PREFIX)
+            .values()
+            .map(|v| Ok(rmp_serde::from_slice(&v?)?))
+            .collect()
+    }
+
+    fn prune_settlements(&self, settled_before: u64) -> Result<u64, anyhow::Error> {
+        let mut removed = 0;
+        for item in self.db.scan_prefix(SETTLEMENT_PREFIX) {
+            let (key, value) = item?;
+            let settlement: Settlement = rmp_serde::from_slice(&value)?;
+            if settlement.settle_date < settled_before {
+                self.db.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn settle_index(&self, node: &str) -> Result<u64, anyhow::Error> {
+        match self.db.get(format!("{}{}", SETTLE_INDEX_PREFIX, node))? {
+            Some(index) => Ok(u64::from_be_bytes(
+                index
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow!("invalid settle index"))?,
+            )),
+            None => Ok(0),
+        }
+    }
+
+    fn set_settle_index(&self, node: &str, index: u64) -> Result<(), anyhow::Error> {
+        let key = format!("{}{}", SETTLE_INDEX_PREFIX, node);
+        self.db.insert(key, &index.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn settle_indexes(&self) -> Result<BTreeMap<String, u64>, anyhow::Error> {
+        let mut indexes = BTreeMap::new();
+        for item in self.db.scan_prefix(SETTLE_INDEX_PREFIX) {
+            let (key, index) = item?;
+            let node = String::from_utf8(key[SETTLE_INDEX_PREFIX.len()..].to_vec())?;
+            let index = index
+                .as_ref()
+                .try_into()
+                .map_err(|_| anyhow!("invalid settle index"))?;
+            indexes.insert(node, u64::from_be_bytes(index));
+        }
+        Ok(indexes)
+    }
+
+    fn schema_version(&self) -> Result<u32, anyhow::Error> {
+        match self.db.get(SCHEMA_VERSION_KEY)? {
+            Some(version) => Ok(u32::from_be_bytes(
+                version
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow!("invalid schema version marker"))?,
+            )),
+            None => Ok(1),
+        }
+    }
+
+    fn migrate(&self, version: u32) -> Result<(), anyhow::Error> {
+        match version {
+            2 => self.migrate_v2()?,
+            // settlements & the cursors are kept under their own keys,
+            // new fields of the existing records get defaulted
+            3..=5 => {}
+            6 => self.migrate_v6()?,
+            _ => bail!("unknown schema version {}", version),
+        }
+        self.set_schema_version(version)
+    }
+
+    fn engine(&self) -> &dyn TokenStore {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{fixtures, schema, StoredSecret};
+
+    fn temporary() -> ::sled::Db {
+        ::sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn migrates_untagged_records() {
+        let db = temporary();
+        let entry = Entry {
+            id: format!("{}v1", ENTRY_PREFIX),
+            secret: StoredSecret::Plain([7; 32]),
+            created_at: 1,
+            expires_at: None,
+            ..fixtures::paid(1000, 0)
+        };
+        // version 1 records were written untagged, without a version marker
+        db.insert(entry.id(), rmp_serde::to_vec_named(&entry).unwrap())
+            .unwrap();
+
+        let store = SledStore::init(db).unwrap();
+        assert_eq!(store.schema_version().unwrap(), 1);
+        assert!(Record::decode(&store.db.get(entry.id()).unwrap().unwrap()).is_err());
+
+        assert_eq!(schema::run(&store).unwrap(), SCHEMA_VERSION as usize - 1);
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        let migrated = store.get(entry.id()).unwrap().unwrap();
+        assert_eq!(migrated.secret, entry.secret);
+        assert_eq!(migrated.quota, entry.quota);
+        assert_eq!(migrated.state, PaymentState::Paid);
+        assert!(migrated.expires_at.unwrap() > now());
+    }
+
+    #[test]
+    fn new_database_starts_at_current_schema() {
+        let store = SledStore::init(temporary()).unwrap();
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(schema::run(&store).unwrap(), 0);
+    }
+}
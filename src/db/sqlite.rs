@@ -0,0 +1,500 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use anyhow::{anyhow, bail, Context};
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use tracing::info;
+
+use crate::lsat::MiliSats;
+
+use super::{
+    debited, now,
+    schema::{SCHEMA_VERSION, UNVERSIONED_TTL},
+    Entry, PaymentState, Settlement, StoredSecret, TokenStore,
+};
+
+/// Current settlements table, the migrations keep their own copy
+/// of the schema they upgrade from
+static SETTLEMENTS_TABLE: &str = "CREATE TABLE settlements (
+    payment_hash TEXT PRIMARY KEY,
+    preimage BLOB NOT NULL,
+    value_msat INTEGER NOT NULL,
+    amt_paid_msat INTEGER NOT NULL,
+    settle_date INTEGER NOT NULL,
+    node TEXT NOT NULL DEFAULT ''
+);";
+
+static META_TABLE: &str = "CREATE TABLE meta (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);";
+
+/// Token store backed by SQLite, handy for operators who
+/// want SQL access to the billing data.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, anyhow::Error> {
+        info!(path, "opening sqlite database");
+        let conn = Connection::open(path).context("failed to open sqlite database")?;
+        let exists: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'tokens'",
+            [],
+            |row| row.get(0),
+        )?;
+        // new databases start at the current schema,
+        // existing ones get there by the migrations
+        if !exists {
+            conn.execute_batch(&format!(
+                "CREATE TABLE tokens (
+                    id TEXT PRIMARY KEY,
+                    secret BLOB NOT NULL,
+                    secret_key_id TEXT,
+                    secret_nonce BLOB,
+                    quota INTEGER NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    expires_at INTEGER,
+                    state TEXT NOT NULL,
+                    payment_hash TEXT,
+                    node TEXT
+                );
+                {}
+                {}
+                PRAGMA user_version = {};",
+                SETTLEMENTS_TABLE,
+                META_TABLE,
+                SCHEMA_VERSION
+            ))?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Add the sealed secret columns, unless the
+    /// table was created with them already
+    fn migrate_v2(conn: &Connection) -> Result<(), anyhow::Error> {
+        let sealable: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('tokens') WHERE name = 'secret_key_id'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !sealable {
+            conn.execute_batch(
+                "ALTER TABLE tokens ADD COLUMN secret_key_id TEXT;
+                 ALTER TABLE tokens ADD COLUMN secret_nonce BLOB;",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the entry with given insert statement,
+    /// returns the number of changed rows
+    fn write(&self, insert: &str, entry: &Entry) -> Result<usize, anyhow::Error> {
+        let (secret, key_id, nonce) = secret_columns(&entry.secret);
+        Ok(self.conn.lock().unwrap().execute(
+            &format!(
+                "{} INTO tokens (id, secret, secret_key_id, secret_nonce, quota,
+                 created_at, expires_at, state, payment_hash, node)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                insert
+            ),
+            params![
+                entry.id,
+                secret,
+                key_id,
+                nonce,
+                entry.quota.0,
+                entry.created_at,
+                entry.expires_at,
+                entry.state.as_str(),
+                entry.payment_hash,
+                entry.node
+            ],
+        )?)
+    }
+
+    /// Load all the entries, results are not streamed
+    /// so the connection doesn't stay locked
+    fn all(&self) -> Result<Vec<Entry>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT * FROM tokens ORDER BY id")?;
+        let entries = stmt
+            .query_map([], from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+}
+
+fn from_row(row: &Row<'_>) -> Result<Entry, rusqlite::Error> {
+    let secret: Vec<u8> = row.get("secret")?;
+    let secret = match row.get::<_, Option<String>>("secret_key_id")? {
+        Some(key_id) => StoredSecret::Sealed {
+            key_id,
+            nonce: row
+                .get::<_, Vec<u8>>("secret_nonce")?
+                .try_into()
+                .map_err(|_| invalid_column("secret_nonce", Type::Blob))?,
+            ciphertext: secret,
+        },
+        None => StoredSecret::Plain(
+            secret
+                .try_into()
+                .map_err(|_| invalid_column("secret", Type::Blob))?,
+        ),
+    };
+    Ok(Entry {
+        id: row.get("id")?,
+        secret,
+        quota: MiliSats(row.get("quota")?),
+        created_at: row.get("created_at")?,
+        expires_at: row.get("expires_at")?,
+        state: row
+            .get::<_, String>("state")?
+            .parse()
+            .map_err(|_| invalid_column("state", Type::Text))?,
+        payment_hash: row.get("payment_hash")?,
+        node: row.get("node")?,
+    })
+}
+
+fn settlement_from_row(row: &Row<'_>) -> Result<Settlement, rusqlite::Error> {
+    Ok(Settlement {
+        payment_hash: row.get("payment_hash")?,
+        preimage: row
+            .get::<_, Vec<u8>>("preimage")?
+            .try_into()
+            .map_err(|_| invalid_column("preimage", Type::Blob))?,
+        value_msat: row.get("value_msat")?,
+        amt_paid_msat: row.get("amt_paid_msat")?,
+        settle_date: row.get("settle_date")?,
+        node: row.get("node")?,
+    })
+}
+
+fn invalid_column(name: &str, kind: Type) -> rusqlite::Error {
+    rusqlite::Error::InvalidColumnType(0, name.to_string(), kind)
+}
+
+/// Secret split into the `secret`, `secret_key_id` and `secret_nonce` columns
+fn secret_columns(secret: &StoredSecret) -> (Vec<u8>, Option<String>, Option<Vec<u8>>) {
+    match secret {
+        StoredSecret::Plain(secret) => (secret.to_vec(), None, None),
+        StoredSecret::Sealed {
+            key_id,
+            nonce,
+            ciphertext,
+        } => (ciphertext.clone(), Some(key_id.clone()), Some(nonce.to_vec())),
+    }
+}
+
+impl TokenStore for SqliteStore {
+    fn get(&self, id: &str) -> Result<Option<Entry>, anyhow::Error> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT * FROM tokens WHERE id = ?1",
+                params![id],
+                from_row,
+            )
+            .optional()?)
+    }
+
+    fn insert(&self, entry: &Entry) -> Result<(), anyhow::Error> {
+        info!(id = entry.id(), "inserting into db");
+        self.write("INSERT OR REPLACE", entry)?;
+        Ok(())
+    }
+
+    fn create(&self, entry: &Entry) -> Result<bool, anyhow::Error> {
+        let created = self.write("INSERT OR IGNORE", entry)? == 1;
+        info!(id = entry.id(), created, "creating in db");
+        Ok(created)
+    }
+
+    fn debit(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        // connection lock makes the read & update atomic
+        let conn = self.conn.lock().unwrap();
+        let entry = conn
+            .query_row(
+                "SELECT * FROM tokens WHERE id = ?1",
+                params![id],
+                from_row,
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("no entry in db for {}", id))?;
+
+        let quota = debited(&entry, amount)?;
+        let changed = conn.execute(
+            "UPDATE tokens SET quota = ?1 WHERE id = ?2",
+            params![quota.0, id],
+        )?;
+        if changed != 1 {
+            bail!("failed to debit entry {}", id);
+        }
+        info!(id, quota = quota.0, "debited in db");
+        Ok(quota)
+    }
+
+    fn refund(&self, id: &str, amount: &MiliSats) -> Result<MiliSats, anyhow::Error> {
+        let quota = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "UPDATE tokens SET quota = quota + ?1 WHERE id = ?2 RETURNING quota",
+                params![amount.0, id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("no entry in db for {}", id))?;
+        info!(id, quota, "refunded in db");
+        Ok(MiliSats(quota))
+    }
+
+    fn set_secret(&self, id: &str, secret: &StoredSecret) -> Result<bool, anyhow::Error> {
+        let (secret, key_id, nonce) = secret_columns(secret);
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE tokens SET secret = ?1, secret_key_id = ?2, secret_nonce = ?3 WHERE id = ?4",
+            params![secret, key_id, nonce, id],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn mark_paid(&self, id: &str, expires_at: u64) -> Result<(), anyhow::Error> {
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE tokens SET state = ?1, expires_at = ?2 WHERE id = ?3",
+            params![PaymentState::Paid.as_str(), expires_at, id],
+        )?;
+        if changed != 1 {
+            bail!("no entry in db for {}", id);
+        }
+        info!(id, expires_at, "marked as paid in db");
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), anyhow::Error> {
+        info!(id, "removing from db");
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM tokens WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id FROM tokens ORDER BY id")?;
+        let ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(ids)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry, anyhow::Error>> + '_> {
+        match self.all() {
+            Ok(entries) => Box::new(entries.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn settle(&self, settlement: &Settlement) -> Result<(), anyhow::Error> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO settlements
+             (payment_hash, preimage, value_msat, amt_paid_msat, settle_date, node)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                settlement.payment_hash,
+                settlement.preimage.to_vec(),
+                settlement.value_msat,
+                settlement.amt_paid_msat,
+                settlement.settle_date,
+                settlement.node
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn settlement(&self, payment_hash: &str) -> Result<Option<Settlement>, anyhow::Error> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT * FROM settlements WHERE payment_hash = ?1",
+                params![payment_hash],
+                settlement_from_row,
+            )
+            .optional()?)
+    }
+
+    fn settlements(&self) -> Result<Vec<Settlement>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT * FROM settlements")?;
+        let settlements = stmt
+            .query_map([], settlement_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(settlements)
+    }
+
+    fn prune_settlements(&self, settled_before: u64) -> Result<u64, anyhow::Error> {
+        let removed = self.conn.lock().unwrap().execute(
+            "DELETE FROM settlements WHERE settle_date < ?1",
+            params![settled_before],
+        )?;
+        Ok(removed as u64)
+    }
+
+    fn settle_index(&self, node: &str) -> Result<u64, anyhow::Error> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM meta WHERE key = ?1",
+                params![format!("settle_index/{}", node)],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    fn set_settle_index(&self, node: &str, index: u64) -> Result<(), anyhow::Error> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![format!("settle_index/{}", node), index],
+        )?;
+        Ok(())
+    }
+
+    fn settle_indexes(&self) -> Result<BTreeMap<String, u64>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT key, value FROM meta WHERE key LIKE 'settle_index/%'")?;
+        let indexes = stmt
+            .query_map([], |row| {
+                let key: String = row.get(0)?;
+                let node = key.trim_start_matches("settle_index/").to_string();
+                Ok((node, row.get(1)?))
+            })?
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        Ok(indexes)
+    }
+
+    fn schema_version(&self) -> Result<u32, anyhow::Error> {
+        let version: u32 = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        // databases created before the versioning have it unset
+        Ok(version.max(1))
+    }
+
+    fn migrate(&self, version: u32) -> Result<(), anyhow::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        match version {
+            2 => Self::migrate_v2(&tx)?,
+            3 => tx.execute_batch(
+                "CREATE TABLE settlements (
+                    payment_hash TEXT PRIMARY KEY,
+                    preimage BLOB NOT NULL,
+                    value_msat INTEGER NOT NULL,
+                    amt_paid_msat INTEGER NOT NULL,
+                    settle_date INTEGER NOT NULL
+                );",
+            )?,
+            4 => {
+                tx.execute_batch("ALTER TABLE tokens ADD COLUMN payment_hash TEXT;")?;
+                tx.execute_batch(META_TABLE)?;
+            }
+            5 => tx.execute_batch(
+                "ALTER TABLE tokens ADD COLUMN node TEXT;
+                 ALTER TABLE settlements ADD COLUMN node TEXT NOT NULL DEFAULT '';",
+            )?,
+            6 => {
+                tx.execute(
+                    "UPDATE tokens SET expires_at = ?1 WHERE expires_at IS NULL",
+                    params![now() + UNVERSIONED_TTL],
+                )?;
+            }
+            _ => bail!("unknown schema version {}", version),
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {};", version))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn engine(&self) -> &dyn TokenStore {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{fixtures, schema};
+
+    /// Database as created before the schema was versioned
+    fn unversioned() -> SqliteStore {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tokens (
+                id TEXT PRIMARY KEY,
+                secret BLOB NOT NULL,
+                quota INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER,
+                state TEXT NOT NULL
+            );",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO tokens (id, secret, quota, created_at, expires_at, state)
+             VALUES (?1, ?2, ?3, ?4, NULL, ?5)",
+            params!["lsat/proxy/secrets/v1", vec![7u8; 32], 1000, 1, "paid"],
+        )
+        .unwrap();
+        SqliteStore {
+            conn: Mutex::new(conn),
+        }
+    }
+
+    #[test]
+    fn migrates_unversioned_database() {
+        let store = unversioned();
+        assert_eq!(store.schema_version().unwrap(), 1);
+        assert_eq!(schema::run(&store).unwrap(), SCHEMA_VERSION as usize - 1);
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        assert!(schema::pending(&store).unwrap().is_empty());
+
+        let entry = store.get("lsat/proxy/secrets/v1").unwrap().unwrap();
+        assert_eq!(entry.secret, StoredSecret::Plain([7; 32]));
+        assert_eq!(entry.quota, MiliSats(1000));
+        assert_eq!(entry.state, PaymentState::Paid);
+        assert!(entry.expires_at.unwrap() > now());
+    }
+
+    #[test]
+    fn migrated_database_takes_sealed_secrets() {
+        let store = unversioned();
+        schema::run(&store).unwrap();
+
+        let sealed = StoredSecret::Sealed {
+            key_id: "2024-02".to_string(),
+            nonce: [1; 12],
+            ciphertext: vec![2; 48],
+        };
+        assert!(store.set_secret("lsat/proxy/secrets/v1", &sealed).unwrap());
+        let entry = store.get("lsat/proxy/secrets/v1").unwrap().unwrap();
+        assert_eq!(entry.secret, sealed);
+        assert_eq!(entry.quota, MiliSats(1000));
+
+        assert!(store.create(&fixtures::entry(10, 0)).unwrap());
+        assert_eq!(store.list().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tracing::{error, info, warn};
+
+use super::{now, PaymentState, Store, TokenStore};
+
+/// Counters of what the sweeper removed since the server started
+#[derive(Debug)]
+pub struct SweepMetrics {
+    pub runs: AtomicU64,
+    pub unpaid_removed: AtomicU64,
+    pub paid_removed: AtomicU64,
+    pub settlements_removed: AtomicU64,
+}
+
+pub static METRICS: SweepMetrics = SweepMetrics {
+    runs: AtomicU64::new(0),
+    unpaid_removed: AtomicU64::new(0),
+    paid_removed: AtomicU64::new(0),
+    settlements_removed: AtomicU64::new(0),
+};
+
+/// Entries removed in a single sweep
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    /// challenges whose invoice expired without being paid
+    pub unpaid: u64,
+    /// paid tokens past their expiry
+    pub paid: u64,
+    /// settlements older than the longest token validity
+    pub settlements: u64,
+}
+
+/// Remove all the expired entries from the store. Challenges whose
+/// invoice got paid are kept, even past the invoice expiry, until the
+/// payment is older than the `retention`, the longest a paid token stays
+/// valid. Settlements are removed once they are older than that too.
+pub fn sweep(store: &dyn TokenStore, retention: u64) -> Result<SweepReport, anyhow::Error> {
+    let now = now();
+    let expired: Vec<_> = store
+        .iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!(error=%e, "Unable to read entry, skipping");
+                None
+            }
+        })
+        .filter(|entry| entry.is_expired(now))
+        .collect();
+
+    let settled_before = now.saturating_sub(retention);
+    let mut report = SweepReport::default();
+    for entry in expired {
+        if entry.state == PaymentState::Pending {
+            let settlement = match entry.payment_hash.as_ref() {
+                Some(payment_hash) => store.settlement(payment_hash)?,
+                None => None,
+            };
+            // paid, but not redeemed yet
+            if matches!(settlement, Some(s) if s.settle_date >= settled_before) {
+                continue;
+            }
+        }
+        store.remove(entry.id())?;
+        match entry.state {
+            PaymentState::Pending => report.unpaid += 1,
+            PaymentState::Paid => report.paid += 1,
+        }
+    }
+    report.settlements = store.prune_settlements(settled_before)?;
+    Ok(report)
+}
+
+/// Periodically sweep the expired entries in the background
+pub fn spawn(store: Store, every: Duration, retention: u64) {
+    info!(every=?every, "Spawning task to sweep expired entries");
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+
+            let store = store.clone();
+            match tokio::task::spawn_blocking(move || sweep(store.as_ref(), retention)).await {
+                Ok(Ok(report)) => {
+                    METRICS.runs.fetch_add(1, Ordering::Relaxed);
+                    METRICS
+                        .unpaid_removed
+                        .fetch_add(report.unpaid, Ordering::Relaxed);
+                    METRICS.paid_removed.fetch_add(report.paid, Ordering::Relaxed);
+                    METRICS
+                        .settlements_removed
+                        .fetch_add(report.settlements, Ordering::Relaxed);
+                    if report.unpaid + report.paid + report.settlements > 0 {
+                        info!(
+                            unpaid = report.unpaid,
+                            paid = report.paid,
+                            settlements = report.settlements,
+                            total_unpaid = METRICS.unpaid_removed.load(Ordering::Relaxed),
+                            total_paid = METRICS.paid_removed.load(Ordering::Relaxed),
+                            "Removed expired entries"
+                        );
+                    }
+                }
+                Ok(Err(e)) => error!(error=%e, "Sweeping expired entries failed"),
+                Err(e) => error!(error=%e, "Sweeper task failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{fixtures, memory::MemoryStore, Entry};
+
+    const RETENTION: u64 = 60 * 60;
+
+    fn insert(store: &MemoryStore, expires_at: u64, state: PaymentState) -> Entry {
+        let mut entry = fixtures::entry(100, expires_at);
+        entry.state = state;
+        store.insert(&entry).unwrap();
+        entry
+    }
+
+    #[test]
+    fn removes_expired_entries() {
+        let store = MemoryStore::default();
+        let unpaid = insert(&store, now() - 10, PaymentState::Pending);
+        let paid = insert(&store, now() - 10, PaymentState::Paid);
+        let valid = insert(&store, now() + 60, PaymentState::Paid);
+
+        let report = sweep(&store, RETENTION).unwrap();
+        assert_eq!((report.unpaid, report.paid), (1, 1));
+        assert!(store.get(unpaid.id()).unwrap().is_none());
+        assert!(store.get(paid.id()).unwrap().is_none());
+        assert!(store.get(valid.id()).unwrap().is_some());
+    }
+
+    #[test]
+    fn keeps_paid_challenges_until_retention() {
+        let store = MemoryStore::default();
+        let recent = insert(&store, now() - 10, PaymentState::Pending);
+        store.settle(&fixtures::settlement(&recent, now() - 20)).unwrap();
+        let old = insert(&store, now() - 10, PaymentState::Pending);
+        store
+            .settle(&fixtures::settlement(&old, now() - RETENTION - 20))
+            .unwrap();
+
+        let report = sweep(&store, RETENTION).unwrap();
+        assert_eq!(report.unpaid, 1);
+        assert!(store.get(recent.id()).unwrap().is_some());
+        assert!(store.get(old.id()).unwrap().is_none());
+        // settlement of the old one is past the retention too
+        assert_eq!(report.settlements, 1);
+        let settled = |entry: &Entry| store.settlement(entry.payment_hash.as_ref().unwrap());
+        assert!(settled(&recent).unwrap().is_some());
+        assert!(settled(&old).unwrap().is_none());
+    }
+}
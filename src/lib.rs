@@ -9,18 +9,12 @@
 // use hyper::service::service_fn;
 // use hyper::{Body, Method, Request, Response, StatusCode};
 
-use serde::Serialize;
-
 pub mod api;
+pub mod cache;
 pub mod config;
 pub mod db;
 pub mod lnd;
 pub mod lsat;
+pub mod pool;
+pub mod routes;
 pub mod upstream;
-
-/// An API error serializable to JSON.
-#[derive(Serialize)]
-struct ErrorMessage {
-    code: u16,
-    message: String,
-}
@@ -0,0 +1,380 @@
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use rand::Rng;
+use tokio::time::sleep;
+use tonic_lnd::{
+    lnrpc::{self, invoice::InvoiceState, AddInvoiceResponse},
+    tonic::{Code, Status},
+};
+use tracing::{info, warn};
+
+use crate::{
+    config,
+    db::{self, Settlement},
+    lsat::MiliSats,
+};
+
+mod node;
+pub mod permissions;
+
+pub use node::{ConnectionStatus, Health, Node};
+pub use tonic_lnd::lnrpc::PaymentHash;
+
+/// Clonable LndClient with a clean api to use with wrap async framework,
+/// spreading the calls over the configured nodes. Invoices are minted
+/// on the healthiest node with the lowest priority, failing over to the
+/// other nodes, so one node going offline doesn't take the paywall down.
+#[derive(Clone)]
+pub struct Client {
+    nodes: Arc<Vec<Node>>,
+}
+
+impl Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.nodes.iter()).finish()
+    }
+}
+
+impl Client {
+    /// Start connecting to all the nodes
+    pub fn init(conf: &[config::Lnd]) -> Client {
+        Self::new(conf.iter().map(Node::init).collect())
+    }
+
+    /// Spread the calls over the nodes, in the order of their priority
+    pub fn new(mut nodes: Vec<Node>) -> Client {
+        nodes.sort_by_key(|n| n.priority());
+        Self {
+            nodes: Arc::new(nodes),
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|n| n.name() == name)
+    }
+
+    /// Health of the best connected node
+    pub fn health(&self) -> Health {
+        let healths: Vec<_> = self.nodes.iter().map(|n| n.health()).collect();
+        if healths.contains(&Health::Connected) {
+            Health::Connected
+        } else if healths.contains(&Health::Degraded) {
+            Health::Degraded
+        } else {
+            Health::Down
+        }
+    }
+
+    /// Check any of the nodes is reachable, no invoice can be issued otherwise
+    pub fn is_reachable(&self) -> bool {
+        self.nodes.iter().any(Node::is_reachable)
+    }
+
+    /// Check a reachable node can receive the amount
+    pub fn can_receive(&self, amount: &MiliSats) -> bool {
+        self.nodes
+            .iter()
+            .any(|n| n.is_reachable() && n.can_receive(amount))
+    }
+
+    /// Check the node is reachable and can receive the amount
+    pub fn can_issue(&self, node: &str, amount: &MiliSats) -> bool {
+        self.node(node)
+            .is_some_and(|n| n.is_reachable() && n.can_receive(amount))
+    }
+
+    /// Connection health of each node
+    pub fn status(&self) -> BTreeMap<String, ConnectionStatus> {
+        self.nodes
+            .iter()
+            .map(|n| (n.name().to_string(), n.status()))
+            .collect()
+    }
+
+    /// Subscribe to invoice events of all the nodes
+    pub async fn subscribe_invoices(&self, store: db::Store) {
+        for node in self.nodes.iter() {
+            node.subscribe_invoices(store.clone()).await;
+        }
+    }
+
+    /// Create a new invoice on the healthiest node able to receive it,
+    /// trying the next one when the node can't be reached. Returns the
+    /// issuing node.
+    pub async fn add_invoice(
+        &self,
+        invoice: lnrpc::Invoice,
+    ) -> Result<(String, AddInvoiceResponse), Status> {
+        let amount = MiliSats(invoice.value_msat as u32);
+        let reachable: Vec<_> = self.nodes.iter().filter(|n| n.is_reachable()).collect();
+        let mut candidates: Vec<_> = reachable
+            .iter()
+            .copied()
+            .filter(|n| n.can_receive(&amount))
+            .collect();
+        if !reachable.is_empty() && candidates.is_empty() {
+            return Err(no_liquidity());
+        }
+        // stable sort keeps the priority order within the same health
+        candidates.sort_by_key(|n| n.health() != Health::Connected);
+
+        let mut last_error = None;
+        for node in candidates {
+            match node.add_invoice(invoice.clone()).await {
+                Ok(add_inv) => return Ok((node.name().to_string(), add_inv)),
+                Err(e) if is_connection_error(&e) => {
+                    warn!(
+                        node = node.name(),
+                        error = %e,
+                        "Unable to add invoice, trying next node"
+                    );
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(not_connected))
+    }
+
+    /// Find invoice in the node that issued it, or in all
+    /// the nodes when it's not known. Returns the node with it.
+    pub async fn lookup_invoice(
+        &self,
+        ph: PaymentHash,
+        node: Option<&str>,
+    ) -> Result<(String, lnrpc::Invoice), Status> {
+        let nodes: Vec<_> = match node.and_then(|name| self.node(name)) {
+            Some(node) => vec![node],
+            None => self.nodes.iter().collect(),
+        };
+
+        let mut last_error = None;
+        for node in nodes {
+            match node.lookup_invoice(ph.clone()).await {
+                Ok(inv) => return Ok((node.name().to_string(), inv)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(not_connected))
+    }
+
+    /// Find the settlement of the invoice, recorded in the store as the invoice
+    /// updates stream in. Settlements missed by the stream are looked up in the
+    /// issuing node and recorded, so each invoice is looked up at most once settled.
+    pub async fn find_settlement(
+        &self,
+        store: &db::Store,
+        r_hash: &[u8],
+        node: Option<&str>,
+    ) -> Result<Option<Settlement>, anyhow::Error> {
+        if let Some(settlement) = store.settlement(&hex::encode(r_hash))? {
+            return Ok(Some(settlement));
+        }
+
+        warn!(node, "checking invoice at LND server");
+        let ph = PaymentHash {
+            r_hash: r_hash.to_vec(),
+            ..Default::default()
+        };
+        let (node, inv) = self.lookup_invoice(ph, node).await?;
+        let settlement = settlement(&node, &inv);
+        if let Some(settlement) = settlement.as_ref() {
+            store.settle(settlement)?;
+        }
+        Ok(settlement)
+    }
+
+    /// Look up the invoices of the pending entries, recording the ones
+    /// settled before the invoice stream got resumable, and of the paid
+    /// entries redeemed before settlements got recorded, so they can be
+    /// served while LND is unreachable. Returns the number of settlements
+    /// found.
+    pub async fn reconcile_pending(&self, store: &db::Store) -> Result<usize, anyhow::Error> {
+        while self.health() == Health::Down {
+            sleep(node::MIN_BACKOFF).await;
+        }
+
+        let now = db::now();
+        let mut pending = vec![];
+        for entry in store.iter() {
+            let entry = entry?;
+            let payment_hash = match entry.payment_hash {
+                Some(payment_hash) if !entry.is_expired(now) => payment_hash,
+                _ => continue,
+            };
+            let unrecorded = match entry.state {
+                db::PaymentState::Pending => true,
+                db::PaymentState::Paid => store.settlement(&payment_hash)?.is_none(),
+            };
+            if unrecorded {
+                pending.push((payment_hash, entry.node));
+            }
+        }
+
+        let mut settled = 0;
+        for (payment_hash, node) in pending.iter() {
+            let r_hash = hex::decode(payment_hash)?;
+            match self.find_settlement(store, &r_hash, node.as_deref()).await {
+                Ok(Some(_)) => settled += 1,
+                Ok(None) => {}
+                Err(e) => warn!(%payment_hash, error=%e, "Unable to reconcile invoice"),
+            }
+        }
+        info!(pending = pending.len(), settled, "Reconciled pending invoices");
+        Ok(settled)
+    }
+}
+
+/// Errors caused by the node not being reachable,
+/// as opposed to the errors of the call itself
+pub fn is_connection_error(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded)
+}
+
+fn not_connected() -> Status {
+    Status::unavailable("not connected to LND")
+}
+
+fn no_liquidity() -> Status {
+    Status::resource_exhausted("not enough inbound liquidity")
+}
+
+/// Random part of the preimage of the proxy invoices, the rest is the tag
+const TAG_NONCE_SIZE: usize = 24;
+
+/// Preimage of a proxy invoice, random bytes followed by a tag derived
+/// from them, so the proxy invoices can be told apart from the other
+/// invoices of a shared node, even when they carry no memo
+fn tagged_preimage() -> [u8; 32] {
+    let mut preimage = [0u8; 32];
+    rand::thread_rng().fill(&mut preimage[..TAG_NONCE_SIZE]);
+    let tag = preimage_tag(&preimage[..TAG_NONCE_SIZE]);
+    preimage[TAG_NONCE_SIZE..].copy_from_slice(&tag);
+    preimage
+}
+
+fn preimage_tag(nonce: &[u8]) -> [u8; 32 - TAG_NONCE_SIZE] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(b"lsat-proxy/invoice");
+    engine.input(nonce);
+    let hash = sha256::Hash::from_engine(engine).into_inner();
+    hash[..32 - TAG_NONCE_SIZE].try_into().expect("hash is longer than the tag")
+}
+
+/// Check the invoice was issued by the proxy
+pub fn is_tagged(inv: &lnrpc::Invoice) -> bool {
+    inv.r_preimage.len() == 32
+        && preimage_tag(&inv.r_preimage[..TAG_NONCE_SIZE])[..] == inv.r_preimage[TAG_NONCE_SIZE..]
+}
+
+/// Settlement of the invoice issued by the `node`, if it's settled
+pub fn settlement(node: &str, inv: &lnrpc::Invoice) -> Option<Settlement> {
+    if inv.state() != InvoiceState::Settled {
+        return None;
+    }
+    Some(Settlement {
+        payment_hash: hex::encode(&inv.r_hash),
+        preimage: inv.r_preimage.clone().try_into().ok()?,
+        value_msat: inv.value_msat as u64,
+        amt_paid_msat: inv.amt_paid_msat as u64,
+        settle_date: inv.settle_date as u64,
+        node: node.to_string(),
+    })
+}
+
+/// Generate the invoice for given value/price, described by the
+/// `memo` or its hash as set in the backend invoice `params`
+pub fn generate_invoice(
+    price: MiliSats,
+    memo: String,
+    params: &config::InvoiceParams,
+) -> tonic_lnd::lnrpc::Invoice {
+    let (memo, description_hash) = if params.description_hash {
+        let hash = sha256::Hash::hash(memo.as_bytes());
+        (String::new(), hash.into_inner().to_vec())
+    } else {
+        (memo, vec![])
+    };
+    tonic_lnd::lnrpc::Invoice {
+        memo,
+        r_preimage: tagged_preimage().to_vec(),
+        description_hash,
+        value_msat: price.0 as i64,
+        expiry: params.expiry as i64,
+        private: params.private,
+        fallback_addr: params.fallback_address.clone().unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoice(r_preimage: Vec<u8>) -> lnrpc::Invoice {
+        lnrpc::Invoice {
+            r_preimage,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn invoices_need_a_reachable_node() {
+        let (alice, bob) = (Node::mock("alice", 1), Node::mock("bob", 1));
+        let lnd = Client::new(vec![alice.clone(), bob.clone()]);
+        let amount = MiliSats(100);
+        assert!(lnd.can_issue("alice", &amount));
+
+        alice.take_down().await;
+        assert!(!lnd.can_issue("alice", &amount));
+        assert!(lnd.can_issue("bob", &amount));
+        assert!(lnd.is_reachable());
+
+        bob.take_down().await;
+        assert!(!lnd.can_issue("bob", &amount));
+        assert!(!lnd.can_receive(&amount));
+        assert!(!lnd.is_reachable());
+        assert_eq!(lnd.health(), Health::Down);
+        let invoice = lnrpc::Invoice {
+            value_msat: 100,
+            ..Default::default()
+        };
+        let err = lnd.add_invoice(invoice).await.unwrap_err();
+        assert!(is_connection_error(&err));
+    }
+
+    #[test]
+    fn proxy_preimages_are_tagged() {
+        for _ in 0..16 {
+            assert!(is_tagged(&invoice(tagged_preimage().to_vec())));
+        }
+    }
+
+    #[test]
+    fn tag_depends_on_the_nonce() {
+        let preimage = tagged_preimage();
+        assert_eq!(
+            preimage_tag(&preimage[..TAG_NONCE_SIZE])[..],
+            preimage[TAG_NONCE_SIZE..]
+        );
+        assert_ne!(
+            preimage_tag(&[0; TAG_NONCE_SIZE]),
+            preimage_tag(&[1; TAG_NONCE_SIZE])
+        );
+    }
+
+    #[test]
+    fn other_preimages_are_not_tagged() {
+        let mut preimage = tagged_preimage();
+        preimage[0] ^= 1;
+        assert!(!is_tagged(&invoice(preimage.to_vec())));
+        assert!(!is_tagged(&invoice(vec![0; 32])));
+        assert!(!is_tagged(&invoice(tagged_preimage()[..31].to_vec())));
+        assert!(!is_tagged(&invoice(vec![])));
+    }
+}
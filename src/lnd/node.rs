@@ -0,0 +1,615 @@
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{
+    sync::{OnceCell, Semaphore, SemaphorePermit},
+    time::{sleep, timeout},
+};
+use tonic_lnd::{
+    lnrpc::{self, AddInvoiceResponse, InvoiceSubscription, PaymentHash},
+    tonic::{Response, Status},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{config, db, lsat::MiliSats};
+
+use super::{is_connection_error, is_tagged, not_connected, settlement};
+
+/// First delay between reconnection attempts
+pub(super) const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Cap of the delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Health of the connection to the LND node
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    /// calls and the invoice stream work
+    Connected,
+    /// connected, but the calls or the invoice stream fail
+    Degraded,
+    /// not connected (yet), or the node stopped answering
+    Down,
+}
+
+/// Connection health with what it's derived from
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub health: Health,
+    /// unix timestamp of the last health change
+    pub since: u64,
+    pub last_error: Option<String>,
+    /// largest amount the node can receive over a single channel, in
+    /// mili-sats, only known with the liquidity checks
+    pub receivable_msat: Option<u64>,
+    #[serde(skip)]
+    connected: bool,
+    #[serde(skip)]
+    calls_ok: bool,
+    #[serde(skip)]
+    stream_ok: bool,
+}
+
+impl ConnectionStatus {
+    fn new() -> Self {
+        Self {
+            health: Health::Down,
+            since: db::now(),
+            last_error: None,
+            receivable_msat: None,
+            connected: false,
+            calls_ok: true,
+            stream_ok: false,
+        }
+    }
+
+    /// Apply the change and re-evaluate the health
+    fn update(&mut self, change: impl FnOnce(&mut Self)) {
+        change(self);
+        let health = match (self.connected, self.calls_ok && self.stream_ok) {
+            (false, _) => Health::Down,
+            (true, false) => Health::Degraded,
+            (true, true) => Health::Connected,
+        };
+        if health != self.health {
+            info!(from = ?self.health, to = ?health, "LND connection health changed");
+            self.health = health;
+            self.since = db::now();
+        }
+    }
+}
+
+/// Exponential backoff between reconnection attempts
+struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { delay: MIN_BACKOFF }
+    }
+}
+
+impl Backoff {
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.delay = MIN_BACKOFF;
+    }
+}
+
+/// Single LND node. Each call works with its own clone of the gRPC
+/// client, sharing the underlying channel, so calls run concurrently
+/// up to the `max_concurrent_calls`. Connection is established in the
+/// background, calls fail with `unavailable` status until then.
+pub struct Node {
+    name: String,
+    priority: u32,
+    lnd: Arc<OnceCell<tonic_lnd::LightningClient>>,
+    permits: Arc<Semaphore>,
+    call_timeout: Duration,
+    status: Arc<RwLock<ConnectionStatus>>,
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            priority: self.priority,
+            lnd: self.lnd.clone(),
+            permits: self.permits.clone(),
+            call_timeout: self.call_timeout,
+            status: self.status.clone(),
+        }
+    }
+}
+
+impl Debug for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Node")
+            .field("name", &self.name)
+            .field("lnd_status", &self.health())
+            .finish()
+    }
+}
+
+impl Node {
+    /// Start connecting to the LND node, retrying with
+    /// exponential backoff until the node is reachable
+    pub fn init(conf: &config::Lnd) -> Node {
+        let node = Self {
+            name: conf.name.clone(),
+            priority: conf.priority,
+            lnd: Arc::new(OnceCell::new()),
+            permits: Arc::new(Semaphore::new(conf.max_concurrent_calls.max(1))),
+            call_timeout: Duration::from_secs(conf.call_timeout.max(1)),
+            status: Arc::new(RwLock::new(ConnectionStatus::new())),
+        };
+
+        if let Some(liquidity) = conf.liquidity.as_ref() {
+            node.watch_liquidity(liquidity.clone());
+        }
+
+        let connecting = node.clone();
+        let conf = conf.clone();
+        tokio::task::spawn(async move {
+            let mut backoff = Backoff::default();
+            loop {
+                let (host, tls_path, mac_path) =
+                    (conf.host.clone(), conf.tls_path.clone(), conf.mac_path.clone());
+                match tonic_lnd::connect(host, tls_path, mac_path).await {
+                    Ok(mut lnd) => {
+                        let _ = connecting.lnd.set(lnd.lightning().clone());
+                        connecting.update(|s| {
+                            s.connected = true;
+                            s.calls_ok = true;
+                        });
+                        info!(node = conf.name, host = conf.host, "Connected to LND");
+                        // established channel reconnects on its own
+                        return;
+                    }
+                    Err(e) => {
+                        let delay = backoff.next();
+                        error!(
+                            node = conf.name,
+                            error = %e,
+                            ?delay,
+                            "Unable to connect to LND, retrying"
+                        );
+                        connecting.update(|s| s.last_error = Some(e.to_string()));
+                        sleep(delay).await;
+                    }
+                }
+            }
+        });
+        node
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Nodes with lower priority are preferred
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    /// Current health of the LND connection
+    pub fn health(&self) -> Health {
+        self.status.read().unwrap().health
+    }
+
+    /// Check the node answers, it's down after a call
+    /// or the invoice stream failed to reach it
+    pub fn is_reachable(&self) -> bool {
+        self.health() != Health::Down
+    }
+
+    /// Connection health with the details
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Check the node can receive the amount, nodes without
+    /// the liquidity checks or not checked yet are assumed to
+    pub fn can_receive(&self, amount: &MiliSats) -> bool {
+        self.status
+            .read()
+            .unwrap()
+            .receivable_msat
+            .is_none_or(|receivable| receivable >= amount.0 as u64)
+    }
+
+    /// Periodically check the inbound liquidity of the node,
+    /// warning when it drops below the configured threshold
+    fn watch_liquidity(&self, conf: config::Liquidity) {
+        let node = self.clone();
+        tokio::task::spawn(async move {
+            let mut low = false;
+            loop {
+                if node.is_reachable() {
+                    match node.list_channels().await {
+                        Ok(channels) => {
+                            let receivable = receivable_msat(&channels);
+                            node.update(|s| s.receivable_msat = Some(receivable));
+                            if receivable < conf.warn_below_msat && !low {
+                                warn!(
+                                    node = node.name,
+                                    receivable_msat = receivable,
+                                    threshold_msat = conf.warn_below_msat,
+                                    "Inbound liquidity dropped below the threshold"
+                                );
+                            } else if receivable >= conf.warn_below_msat && low {
+                                info!(
+                                    node = node.name,
+                                    receivable_msat = receivable,
+                                    "Inbound liquidity recovered"
+                                );
+                            }
+                            low = receivable < conf.warn_below_msat;
+                        }
+                        Err(e) => {
+                            warn!(node = node.name, error=%e, "Unable to list channels")
+                        }
+                    }
+                }
+                sleep(Duration::from_secs(conf.interval.max(1))).await;
+            }
+        });
+    }
+
+    /// Clone of the gRPC client, cheap as it shares the channel
+    #[allow(clippy::result_large_err)] // same error as the calls
+    fn lightning(&self) -> Result<tonic_lnd::LightningClient, Status> {
+        self.lnd.get().cloned().ok_or_else(not_connected)
+    }
+
+    /// Wait for a free slot to make a call
+    async fn permit(&self) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire()
+            .await
+            .expect("call permits are never closed")
+    }
+
+    /// Make the call once there's a free slot, calls not answered within
+    /// the `call_timeout` fail like the ones the node can't be reached for
+    async fn call<T>(
+        &self,
+        call: impl Future<Output = Result<Response<T>, Status>>,
+    ) -> Result<T, Status> {
+        let _permit = self.permit().await;
+        let result = match timeout(self.call_timeout, call).await {
+            Ok(result) => result.map(Response::into_inner),
+            Err(_) => Err(Status::deadline_exceeded("LND call timed out")),
+        };
+        self.track(&result);
+        result
+    }
+
+    fn update(&self, change: impl FnOnce(&mut ConnectionStatus)) {
+        self.status.write().unwrap().update(change);
+    }
+
+    /// Track the outcome of a call, errors other than the
+    /// connection ones (e.g. invoice not found) are fine.
+    /// The node is down until a call or the invoice
+    /// subscription gets through again.
+    fn track<T>(&self, result: &Result<T, Status>) {
+        match result {
+            Ok(_) => self.update(|s| {
+                s.connected = true;
+                s.calls_ok = true;
+            }),
+            Err(e) if is_connection_error(e) => {
+                self.update(|s| {
+                    s.connected = false;
+                    s.calls_ok = false;
+                    s.last_error = Some(e.message().to_string());
+                })
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Subscribe to invoice events, settled invoices get recorded in the store.
+    /// The subscription resumes from the last recorded settle index, so LND
+    /// replays the settlements missed while reconnecting or not running.
+    pub async fn subscribe_invoices(&self, store: db::Store) {
+        let client = self.clone();
+        let node = self.name.clone();
+
+        info!(node, "Sprawing task to handle invoice stream updates");
+        tokio::task::spawn(async move {
+            let mut backoff = Backoff::default();
+            loop {
+                let settle_index = match store.settle_index(&node) {
+                    Ok(index) => index,
+                    Err(e) => {
+                        let delay = backoff.next();
+                        error!(node, error=%e, ?delay, "Unable to read settle index, retrying");
+                        sleep(delay).await;
+                        continue;
+                    }
+                };
+                let subscription = InvoiceSubscription {
+                    settle_index,
+                    ..Default::default()
+                };
+                let inv_stream = match client.lightning() {
+                    Ok(mut lnd) => lnd.subscribe_invoices(subscription).await,
+                    Err(e) => Err(e),
+                };
+
+                let mut inv_stream = match inv_stream {
+                    Ok(inv_stream) => inv_stream.into_inner(),
+                    Err(e) => {
+                        let delay = backoff.next();
+                        error!(node, error=%e, ?delay, "Unable to subscribe to invoices, retrying");
+                        client.update(|s| {
+                            s.connected &= !is_connection_error(&e);
+                            s.stream_ok = false;
+                            s.last_error = Some(e.message().to_string());
+                        });
+                        sleep(delay).await;
+                        continue;
+                    }
+                };
+                info!(node, settle_index, "Subscribed to invoice updates");
+                client.update(|s| {
+                    s.connected = true;
+                    s.stream_ok = true;
+                });
+                backoff.reset();
+
+                loop {
+                    match inv_stream.message().await {
+                        Ok(Some(inv)) => {
+                            if is_tagged(&inv) {
+                                info!(node, inv=?inv, "Invoice update arrived");
+                            } else {
+                                debug!(node, "Ignoring update of an invoice not issued by us");
+                            }
+                            if let Err(e) = record_settlement(&store, &node, &inv) {
+                                error!(node, error=%e, "Unable to record invoice settlement");
+                                break;
+                            }
+                        }
+                        Ok(None) => {
+                            warn!(node, "Invoice stream ended, resubscribing");
+                            break;
+                        }
+                        Err(e) => {
+                            error!(node, error=%e, "Invoice stream failed, resubscribing");
+                            client.update(|s| {
+                                s.connected &= !is_connection_error(&e);
+                                s.last_error = Some(e.message().to_string());
+                            });
+                            break;
+                        }
+                    }
+                }
+                client.update(|s| s.stream_ok = false);
+                sleep(backoff.next()).await;
+            }
+        });
+    }
+
+    /// Create a new invoice with the node
+    pub async fn add_invoice(
+        &self,
+        invoice: tonic_lnd::lnrpc::Invoice,
+    ) -> Result<AddInvoiceResponse, Status> {
+        let mut lnd = self.lightning()?;
+        self.call(lnd.add_invoice(invoice)).await
+    }
+
+    /// Active channels of the node
+    async fn list_channels(&self) -> Result<Vec<lnrpc::Channel>, Status> {
+        let mut lnd = self.lightning()?;
+        let req = lnrpc::ListChannelsRequest {
+            active_only: true,
+            ..Default::default()
+        };
+        Ok(self.call(lnd.list_channels(req)).await?.channels)
+    }
+
+    /// Find invoice in the node
+    pub async fn lookup_invoice(&self, ph: PaymentHash) -> Result<lnrpc::Invoice, Status> {
+        let mut lnd = self.lightning()?;
+        self.call(lnd.lookup_invoice(ph)).await
+    }
+}
+
+/// Record the settlement of a proxy invoice and move the node settle index
+/// past it, the index only moves once the settlement is safely stored.
+/// Settlements of the other invoices on the node only move the index.
+fn record_settlement(
+    store: &db::Store,
+    node: &str,
+    inv: &lnrpc::Invoice,
+) -> Result<(), anyhow::Error> {
+    if let Some(settlement) = settlement(node, inv).filter(|_| is_tagged(inv)) {
+        store.settle(&settlement)?;
+    }
+    if inv.settle_index > store.settle_index(node)? {
+        store.set_settle_index(node, inv.settle_index)?;
+    }
+    Ok(())
+}
+
+/// Largest amount a single payment can bring in over one of the channels:
+/// the remote balance above the reserve the remote has to keep. Summed
+/// balances would overstate it, a payment has to fit a single channel.
+fn receivable_msat(channels: &[lnrpc::Channel]) -> u64 {
+    channels
+        .iter()
+        .map(|c| {
+            let reserve = c
+                .remote_constraints
+                .as_ref()
+                .map_or(0, |rc| rc.chan_reserve_sat);
+            (c.remote_balance.max(0) as u64).saturating_sub(reserve) * 1000
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+impl Node {
+    /// Node that never connects, reachable until a call made
+    /// against a mock fails the way an unreachable node's does
+    pub(crate) fn mock(name: &str, max_concurrent_calls: usize) -> Node {
+        let node = Node {
+            name: name.to_string(),
+            priority: 0,
+            lnd: Arc::new(OnceCell::new()),
+            permits: Arc::new(Semaphore::new(max_concurrent_calls)),
+            call_timeout: Duration::from_secs(5),
+            status: Arc::new(RwLock::new(ConnectionStatus::new())),
+        };
+        node.update(|s| {
+            s.connected = true;
+            s.stream_ok = true;
+        });
+        node
+    }
+
+    /// Make a call answered with the `result`
+    pub(crate) async fn mock_call<T>(&self, result: Result<T, Status>) -> Result<T, Status> {
+        self.call(async { result.map(Response::new) }).await
+    }
+
+    /// Fail a call the way an unreachable node does
+    pub(crate) async fn take_down(&self) {
+        let refused = Status::unavailable("connection refused");
+        self.mock_call::<()>(Err(refused)).await.unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::sync::Barrier;
+
+    use super::*;
+
+    /// Calls to the mock node, answered only once `batch` of them
+    /// are in flight together, counting the most seen in flight
+    struct MockCalls {
+        barrier: Barrier,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl MockCalls {
+        fn new(batch: usize) -> Arc<Self> {
+            Arc::new(Self {
+                barrier: Barrier::new(batch),
+                in_flight: AtomicUsize::new(0),
+                max_in_flight: AtomicUsize::new(0),
+            })
+        }
+
+        async fn call(&self) -> Result<Response<()>, Status> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            self.barrier.wait().await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(Response::new(()))
+        }
+
+        /// Make the calls all at once, returns the most seen in flight
+        async fn run(self: &Arc<Self>, node: &Node, calls: usize) -> usize {
+            let handles: Vec<_> = (0..calls)
+                .map(|_| {
+                    let (node, mock) = (node.clone(), self.clone());
+                    tokio::spawn(async move { node.call(mock.call()).await })
+                })
+                .collect();
+            for handle in handles {
+                handle.await.unwrap().unwrap();
+            }
+            self.max_in_flight.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn calls_run_concurrently() {
+        // serialized calls would never all meet at the barrier and time out
+        let calls = 64;
+        let max_in_flight = MockCalls::new(calls).run(&Node::mock("mock", calls), calls).await;
+        assert_eq!(max_in_flight, calls);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrency_is_bounded() {
+        // answered in rounds of 4, a fifth call in flight would be counted
+        let max_in_flight = MockCalls::new(4).run(&Node::mock("mock", 4), 16).await;
+        assert_eq!(max_in_flight, 4);
+    }
+
+    #[tokio::test]
+    async fn slow_calls_time_out() {
+        let mut node = Node::mock("mock", 1);
+        node.call_timeout = Duration::from_millis(50);
+        let err = node
+            .call(std::future::pending::<Result<Response<()>, Status>>())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic_lnd::tonic::Code::DeadlineExceeded);
+        // timed out calls count as the node being unreachable
+        assert_eq!(node.health(), Health::Down);
+    }
+
+    #[tokio::test]
+    async fn unreachable_node_goes_down() {
+        let node = Node::mock("mock", 1);
+        assert_eq!(node.health(), Health::Connected);
+
+        // errors of the call itself leave the node up
+        let not_found = Status::not_found("invoice not found");
+        node.mock_call::<()>(Err(not_found)).await.unwrap_err();
+        assert_eq!(node.health(), Health::Connected);
+
+        let refused = Status::unavailable("connection refused");
+        node.mock_call::<()>(Err(refused)).await.unwrap_err();
+        assert_eq!(node.health(), Health::Down);
+        assert_eq!(node.status().last_error.as_deref(), Some("connection refused"));
+
+        node.mock_call(Ok(())).await.unwrap();
+        assert_eq!(node.health(), Health::Connected);
+    }
+
+    fn channel(remote_balance: i64, chan_reserve_sat: u64) -> lnrpc::Channel {
+        lnrpc::Channel {
+            remote_balance,
+            remote_constraints: Some(lnrpc::ChannelConstraints {
+                chan_reserve_sat,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn receivable_is_the_largest_channel_above_reserve() {
+        let channels = [
+            channel(50_000, 1_000),
+            channel(80_000, 40_000),
+            channel(500, 1_000),
+        ];
+        assert_eq!(receivable_msat(&channels), 49_000_000);
+        assert_eq!(receivable_msat(&[]), 0);
+    }
+}
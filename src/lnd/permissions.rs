@@ -0,0 +1,291 @@
+use std::{collections::BTreeSet, fmt::Display, fs};
+
+use anyhow::{bail, Context};
+use macaroon::Macaroon;
+use prost::Message;
+use tracing::{info, warn};
+
+use crate::config;
+
+/// Permission needed by the proxy, with the LND calls using it
+#[derive(Debug)]
+pub struct Requirement {
+    pub entity: &'static str,
+    pub action: &'static str,
+    pub methods: &'static [&'static str],
+}
+
+/// Minimal permission set of the macaroon, an invoice-only macaroon can
+/// be baked with `lncli bakemacaroon invoices:read invoices:write`
+pub static REQUIRED: &[Requirement] = &[
+    Requirement {
+        entity: "invoices",
+        action: "read",
+        methods: &[
+            "/lnrpc.Lightning/LookupInvoice",
+            "/lnrpc.Lightning/SubscribeInvoices",
+        ],
+    },
+    Requirement {
+        entity: "invoices",
+        action: "write",
+        methods: &["/lnrpc.Lightning/AddInvoice"],
+    },
+];
+
+/// Permission needed by the inbound liquidity checks
+pub static LIQUIDITY: Requirement = Requirement {
+    entity: "offchain",
+    action: "read",
+    methods: &["/lnrpc.Lightning/ListChannels"],
+};
+
+/// Permissions needed with the node configuration
+pub fn required(conf: &config::Lnd) -> Vec<&'static Requirement> {
+    let mut required: Vec<_> = REQUIRED.iter().collect();
+    if conf.liquidity.is_some() {
+        required.push(&LIQUIDITY);
+    }
+    required
+}
+
+/// Permission granted by a macaroon, `uri` permissions
+/// grant a single method with the method as the action
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Permission {
+    pub entity: String,
+    pub action: String,
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.entity, self.action)
+    }
+}
+
+/// Outcome of comparing the granted permissions with the required ones
+#[derive(Debug, Default)]
+pub struct Report {
+    pub missing: Vec<String>,
+    pub excess: Vec<Permission>,
+}
+
+/// LND macaroon identifier, a version byte followed by this message
+#[derive(Clone, PartialEq, Message)]
+struct MacaroonId {
+    #[prost(bytes = "vec", tag = "1")]
+    nonce: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    storage_id: Vec<u8>,
+    #[prost(message, repeated, tag = "3")]
+    ops: Vec<Op>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Op {
+    #[prost(string, tag = "1")]
+    entity: String,
+    #[prost(string, repeated, tag = "2")]
+    actions: Vec<String>,
+}
+
+/// Version of the LND macaroon identifiers
+const MACAROON_ID_VERSION: u8 = 3;
+
+/// Read the permissions granted by the macaroon file
+pub fn granted(mac_path: &str) -> Result<BTreeSet<Permission>, anyhow::Error> {
+    let data = fs::read(mac_path).with_context(|| format!("unable to read {}", mac_path))?;
+    let mac = Macaroon::deserialize_binary(&data)
+        .map_err(|e| anyhow::anyhow!("malformed macaroon {}: {:?}", mac_path, e))?;
+
+    parse_identifier(&mac.identifier().0)
+        .with_context(|| format!("{} is not an LND macaroon", mac_path))
+}
+
+/// Permissions listed in the LND macaroon identifier
+fn parse_identifier(id: &[u8]) -> Result<BTreeSet<Permission>, anyhow::Error> {
+    match id.split_first() {
+        Some((&MACAROON_ID_VERSION, id)) => {
+            let id = MacaroonId::decode(id).context("malformed macaroon identifier")?;
+            Ok(id
+                .ops
+                .into_iter()
+                .flat_map(|op| {
+                    op.actions.into_iter().map(move |action| Permission {
+                        entity: op.entity.clone(),
+                        action,
+                    })
+                })
+                .collect())
+        }
+        _ => bail!("unknown macaroon identifier version"),
+    }
+}
+
+/// Compare the granted permissions with the required ones
+pub fn compare(granted: &BTreeSet<Permission>, required: &[&Requirement]) -> Report {
+    let grants = |entity: &str, action: &str| {
+        granted
+            .iter()
+            .any(|p| p.entity == entity && p.action == action)
+    };
+
+    let mut report = Report::default();
+    for req in required.iter() {
+        if grants(req.entity, req.action) {
+            continue;
+        }
+        // the permission can also be granted method by method
+        for method in req.methods.iter() {
+            if !grants("uri", method) {
+                report.missing.push(format!("{}:{} ({})", req.entity, req.action, method));
+            }
+        }
+    }
+
+    report.excess = granted
+        .iter()
+        .filter(|p| {
+            !required.iter().any(|req| {
+                (p.entity == req.entity && p.action == req.action)
+                    || (p.entity == "uri" && req.methods.contains(&p.action.as_str()))
+            })
+        })
+        .cloned()
+        .collect();
+    report
+}
+
+/// Check the macaroon of the node grants the required permissions,
+/// and nothing more unless `allow_excess_permissions` is set
+pub fn check(conf: &config::Lnd) -> Result<(), anyhow::Error> {
+    let granted = granted(&conf.mac_path)?;
+    let report = compare(&granted, &required(conf));
+    if !report.missing.is_empty() {
+        bail!(
+            "macaroon of LND node {} is missing permissions: {}",
+            conf.name,
+            report.missing.join(", ")
+        );
+    }
+    if !report.excess.is_empty() {
+        let excess = report
+            .excess
+            .iter()
+            .map(Permission::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !conf.allow_excess_permissions {
+            bail!(
+                "macaroon of LND node {} grants more than needed: {}, bake an invoice-only \
+                 macaroon (see `cli lnd-permissions`) or set allow_excess_permissions",
+                conf.name,
+                excess
+            );
+        }
+        warn!(
+            node = conf.name,
+            excess,
+            "!!! LND macaroon grants more permissions than the proxy needs !!!"
+        );
+        return Ok(());
+    }
+    info!(node = conf.name, "LND macaroon has the minimal permissions");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permission(entity: &str, action: &str) -> Permission {
+        Permission {
+            entity: entity.to_string(),
+            action: action.to_string(),
+        }
+    }
+
+    fn identifier(ops: &[(&str, &[&str])]) -> Vec<u8> {
+        let id = MacaroonId {
+            nonce: vec![1; 8],
+            storage_id: vec![0],
+            ops: ops
+                .iter()
+                .map(|(entity, actions)| Op {
+                    entity: entity.to_string(),
+                    actions: actions.iter().map(|a| a.to_string()).collect(),
+                })
+                .collect(),
+        };
+        [vec![MACAROON_ID_VERSION], id.encode_to_vec()].concat()
+    }
+
+    #[test]
+    fn parses_lnd_identifier() {
+        let id = identifier(&[("invoices", &["read", "write"]), ("offchain", &["read"])]);
+        let granted = parse_identifier(&id).unwrap();
+        assert_eq!(
+            granted.into_iter().collect::<Vec<_>>(),
+            vec![
+                permission("invoices", "read"),
+                permission("invoices", "write"),
+                permission("offchain", "read"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_identifier_version() {
+        let mut id = identifier(&[("invoices", &["read"])]);
+        id[0] = 2;
+        assert!(parse_identifier(&id).is_err());
+        assert!(parse_identifier(&[]).is_err());
+    }
+
+    #[test]
+    fn minimal_set_has_nothing_missing_or_excess() {
+        let granted = BTreeSet::from([
+            permission("invoices", "read"),
+            permission("invoices", "write"),
+        ]);
+        let report = compare(&granted, &REQUIRED.iter().collect::<Vec<_>>());
+        assert!(report.missing.is_empty());
+        assert!(report.excess.is_empty());
+    }
+
+    #[test]
+    fn uri_permissions_grant_single_methods() {
+        let granted = BTreeSet::from([
+            permission("invoices", "read"),
+            permission("uri", "/lnrpc.Lightning/AddInvoice"),
+        ]);
+        let report = compare(&granted, &REQUIRED.iter().collect::<Vec<_>>());
+        assert!(report.missing.is_empty());
+        assert!(report.excess.is_empty());
+    }
+
+    #[test]
+    fn reports_missing_and_excess_permissions() {
+        let granted = BTreeSet::from([
+            permission("invoices", "read"),
+            permission("onchain", "write"),
+            permission("uri", "/lnrpc.Lightning/SendPaymentSync"),
+        ]);
+        let required = [&REQUIRED[0], &REQUIRED[1], &LIQUIDITY];
+        let report = compare(&granted, &required);
+        assert_eq!(
+            report.missing,
+            vec![
+                "invoices:write (/lnrpc.Lightning/AddInvoice)".to_string(),
+                "offchain:read (/lnrpc.Lightning/ListChannels)".to_string(),
+            ]
+        );
+        assert_eq!(
+            report.excess,
+            vec![
+                permission("onchain", "write"),
+                permission("uri", "/lnrpc.Lightning/SendPaymentSync"),
+            ]
+        );
+    }
+}
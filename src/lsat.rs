@@ -4,7 +4,10 @@ use std::{
 };
 
 use anyhow::{bail, Context};
-use bitcoin_hashes::{sha256, Hash};
+use bitcoin_hashes::{
+    hmac::{Hmac, HmacEngine},
+    sha256, Hash, HashEngine,
+};
 use lightning::ln::{PaymentHash, PaymentPreimage};
 use lightning_invoice::Invoice;
 use regex::Regex;
@@ -90,13 +93,23 @@ pub trait ToSha256 {
 impl Id {
     pub fn new(payment_hash: PaymentHash) -> Self {
         let mut rng = rand::thread_rng();
-        let token_id = Token(rng.gen());
+        Self::with_token_id(payment_hash, rng.gen())
+    }
+
+    /// id with the token id picked upfront, so it can
+    /// be shown in the invoice before the id exists
+    pub fn with_token_id(payment_hash: PaymentHash, token_id: [u8; TOKEN_ID_SIZE]) -> Self {
         Self {
             version: ID_VERSION,
             payment_hash,
-            token_id,
+            token_id: Token(token_id),
         }
     }
+
+    /// hex-encoded unique token identifier
+    pub fn token_id(&self) -> String {
+        hex::encode(self.token_id.0)
+    }
 }
 
 impl ToSha256 for Id {
@@ -134,14 +147,17 @@ impl ToSha256 for PaymentPreimage {
 }
 
 impl ToSha256 for HashMap<String, String> {
-    // TODO: this is quite costly, improve if higher traffix
+    /// keys & values are length prefixed, so `{"ab": "c"}`
+    /// and `{"a": "bc"}` don't hash the same
     fn to_sha256(&self) -> Result<sha256::Hash, anyhow::Error> {
-        let str = &self.iter()
-            .sorted_by_key(|(k, _)| *k)
-            .flat_map(|(x, y)| [x.to_string(), y.to_string()])
-            .collect::<Vec<_>>()
-            .join("");
-        Ok(sha256::Hash::hash(&str.as_bytes()))
+        let mut engine = sha256::Hash::engine();
+        for (key, value) in self.iter().sorted_by_key(|(k, _)| *k) {
+            for field in [key, value] {
+                engine.input(&(field.len() as u64).to_be_bytes());
+                engine.input(field.as_bytes());
+            }
+        }
+        Ok(sha256::Hash::from_engine(engine))
     }
 }
 
@@ -161,6 +177,45 @@ impl FromPreimage for PaymentPreimage {
     }
 }
 
+/// Invoice of a new LSAT, along with the token id shown in the
+/// invoice memo and the name of the node that issued it
+#[derive(Debug)]
+pub struct Challenge {
+    pub token_id: [u8; TOKEN_ID_SIZE],
+    pub node: String,
+    pub invoice: Invoice,
+}
+
+impl Challenge {
+    /// obtain an invoice from LND and extract the payment request & hash
+    pub async fn issue(lnd: lnd::Client, backend: &Backend) -> Result<Self, anyhow::Error> {
+        // generate new invoice via lnd first. We need to know the payment hash
+        // so we can add it as a caveat to the macaroon.
+        let token_id = rand::thread_rng().gen();
+        let memo = backend.invoice_memo(&hex::encode(token_id));
+        let (node, resp) = lnd
+            .add_invoice(lnd::generate_invoice(
+                backend.amount_total(),
+                memo,
+                &backend.invoice,
+            ))
+            .await
+            .context("failed to generate invoice")?;
+
+        let invoice = str::parse::<Invoice>(&resp.payment_request)?;
+        Ok(Self {
+            token_id,
+            node,
+            invoice,
+        })
+    }
+
+    /// unix timestamp the invoice expires at
+    pub fn expires_at(&self) -> u64 {
+        (self.invoice.duration_since_epoch() + self.invoice.expiry_time()).as_secs()
+    }
+}
+
 impl Lsat {
     /// initalize LSAT with the macaroon value
     /// useful when parsing incoming headers
@@ -192,35 +247,44 @@ impl Lsat {
             .expect("macaroon predicate not found"))
     }
 
-    /// obtain an invoice from LND and extract the payment request & hash
-    async fn new_challenge(lnd: lnd::Client, price: MiliSats) -> Result<Invoice, anyhow::Error> {
-        // generate new invoice via lnd first. We need to know the payment hash
-        // so we can add it as a caveat to the macaroon.
-        let resp = lnd
-            .add_invoice(lnd::generate_invoice(price))
-            .await
-            .context("failed to generate invoice")?;
-
-        let inv = str::parse::<Invoice>(&resp.payment_request)?;
-        Ok(inv)
-    }
-
     pub async fn generate_challange(
         lnd: lnd::Client,
+        pool: &InvoicePool,
+        store: &db::Store,
+        tokens: &Tokens,
         backend: &Backend,
         body_sha: &sha256::Hash,
     ) -> Result<Response, anyhow::Error> {
         // We'll start by retrieving a new challenge in the form of a Lightning
-        // payment request to present the requester of the LSAT with.
-        let inv = Lsat::new_challenge(lnd, backend.amount_total()).await?;
+        // payment request to present the requester of the LSAT with, pre-created
+        // ones are served first.
+        let challenge = match pool.take(backend, &lnd) {
+            Some(challenge) => challenge,
+            None => Challenge::issue(lnd, backend).await?,
+        };
+        let invoice_expiry = challenge.expires_at();
+        let Challenge {
+            token_id,
+            node,
+            invoice: inv,
+        } = challenge;
 
         // We can then proceed to mint the LSAT with a unique identifier that is
         // mapped to a unique secret.
-        let id = Id::new(PaymentHash(inv.payment_hash().into_inner()));
-
-        let secret = MacaroonKey::generate(&id.to_sha256()?);
-
-        db::Entry::insert(&id, &secret, backend.amount_total()).await?;
+        let id = Id::with_token_id(PaymentHash(inv.payment_hash().into_inner()), token_id);
+
+        let secret = derive_secret(&id, tokens.root_key.as_ref())?;
+
+        // with lazy persistence everything needed is recovered from
+        // the macaroon and the invoice once the token gets redeemed
+        if tokens.persistence == Persistence::Eager {
+            // unpaid entry is no longer needed once the invoice expires
+            let mut entry =
+                db::Entry::new(&id, &secret, backend.amount_total(), invoice_expiry)?;
+            // the invoice is looked up in the node that issued it
+            entry.node = Some(node);
+            store.insert(&entry)?;
+        }
 
         let mut mac = Macaroon::create(
             Some("https://lsat-playground.bucko.vercel.app".to_string()),
@@ -229,10 +293,11 @@ impl Lsat {
         )?;
 
         // apply restrictions to the LSAT/macaroon.
-        let curr_ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        // TODO: make this configurable
-        mac.add_first_party_caveat(format!("time<{}", curr_ts + 120).into());
-        mac.add_first_party_caveat(format!("path={}", backend.path).into());
+        // the token can't be used past the latest payment plus its validity
+        mac.add_first_party_caveat(format!("time<{}", invoice_expiry + backend.token_ttl).into());
+        // bound to the requested path, not the route pattern, so a token bought
+        // for one path parameter value can't be used with another one
+        mac.add_first_party_caveat(format!("path={}", backend.request_path).into());
         mac.add_first_party_caveat(format!("payload={}", body_sha.encode_hex::<String>()).into());
 
 
@@ -249,9 +314,9 @@ impl Lsat {
         Ok(res)
     }
 
-    pub async fn verify(&self, secret: &MacaroonKey, path: &str, body_sha: sha256::Hash) -> Result<(), anyhow::Error> {
+    pub async fn verify(&self, secret: &MacaroonKey, root_key: Option<&Secret>, path: &str, _body_sha: sha256::Hash) -> Result<(), anyhow::Error> {
         // ensure the LSAT was minted by us.
-        let signature = MacaroonKey::generate(&self.id.to_sha256()?);
+        let signature = derive_secret(&self.id, root_key)?;
 
         info!(
             "LSAT mac signature is {} raw {} sig {}",
@@ -294,6 +359,20 @@ impl Lsat {
     }
 }
 
+/// Derive the macaroon secret for the LSAT id. With a root key
+/// the secret can't be derived from the (public) id alone.
+pub fn derive_secret(id: &Id, root_key: Option<&Secret>) -> Result<MacaroonKey, anyhow::Error> {
+    let id_sha = id.to_sha256()?;
+    Ok(match root_key {
+        Some(root_key) => {
+            let mut engine = HmacEngine::<sha256::Hash>::new(root_key.expose().as_bytes());
+            engine.input(&id_sha.into_inner());
+            MacaroonKey::generate(&Hmac::<sha256::Hash>::from_engine(engine).into_inner())
+        }
+        None => MacaroonKey::generate(&id_sha),
+    })
+}
+
 pub trait HeadersParser {
     fn parse_lsat(self) -> Result<(Lsat, PaymentPreimage), anyhow::Error>;
 }
@@ -353,7 +432,11 @@ use warp::{
     reply::Response,
 };
 
-use crate::{config::Backend, db, lnd};
+use crate::{
+    config::{Backend, Persistence, Secret, Tokens},
+    db, lnd,
+    pool::InvoicePool,
+};
 
 fn timestamp_verifier(caveat: &ByteString) -> bool {
     if !caveat.0.starts_with(b"time<") {
@@ -369,7 +452,7 @@ fn timestamp_verifier(caveat: &ByteString) -> bool {
         .unwrap()
         .as_secs();
 
-    let ts: u64 = strcaveat.split('<').last().unwrap().trim().parse().unwrap();
+    let ts: u64 = strcaveat.split('<').next_back().unwrap().trim().parse().unwrap();
     info!("Checking timestamps {} < {}", curr_ts, ts);
     curr_ts < ts
 }
@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::sleep};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{self, Backend},
+    db, lnd,
+    lsat::Challenge,
+};
+
+/// Delay between the checks of pools nobody takes from
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Invoices pre-created for a backend at its standard price
+struct Pool {
+    conf: config::Pool,
+    backend: Backend,
+    invoices: Mutex<VecDeque<Challenge>>,
+    refill: Notify,
+}
+
+impl Pool {
+    /// Drop the invoices that wouldn't leave enough time to pay them
+    fn expire(&self) {
+        let deadline = db::now() + self.conf.min_validity(&self.backend.invoice);
+        let mut invoices = self.invoices.lock().unwrap();
+        let before = invoices.len();
+        invoices.retain(|c| c.expires_at() > deadline);
+        if invoices.len() < before {
+            debug!(
+                backend = self.backend.name,
+                expired = before - invoices.len(),
+                "Dropped expiring pooled invoices"
+            );
+        }
+    }
+
+    /// Number of invoices to create, the pool is filled up
+    /// once it drops below the refill threshold
+    fn missing(&self) -> usize {
+        let len = self.invoices.lock().unwrap().len();
+        if len < self.conf.refill_below() {
+            self.conf.size - len
+        } else {
+            0
+        }
+    }
+}
+
+/// Per-backend pools of pre-created invoices, so challenges don't wait
+/// for LND. Only backends with an `invoice.pool` section get one.
+#[derive(Clone, Default)]
+pub struct InvoicePool {
+    pools: Arc<HashMap<String, Arc<Pool>>>,
+}
+
+impl Debug for InvoicePool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InvoicePool")
+            .field("backends", &self.pools.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl InvoicePool {
+    /// Set up the pools and start filling them in the background
+    pub fn new(backends: &[Backend], lnd: lnd::Client) -> Self {
+        let mut pools = HashMap::new();
+        for backend in backends {
+            if let Some(conf) = &backend.invoice.pool {
+                info!(backend = backend.name, size = conf.size, "Enabling invoice pool");
+                let pool = Arc::new(Pool {
+                    conf: conf.clone(),
+                    backend: backend.clone(),
+                    invoices: Mutex::new(VecDeque::with_capacity(conf.size)),
+                    refill: Notify::new(),
+                });
+                tokio::spawn(refill(pool.clone(), lnd.clone()));
+                pools.insert(backend.name.clone(), pool);
+            }
+        }
+        Self {
+            pools: Arc::new(pools),
+        }
+    }
+
+    /// Take a pooled invoice for the backend, if there's one at the
+    /// price of the request and its node can still receive it. The
+    /// expiring invoices and the ones of unreachable nodes are dropped.
+    pub fn take(&self, backend: &Backend, lnd: &lnd::Client) -> Option<Challenge> {
+        let pool = self.pools.get(&backend.name)?;
+        let amount = backend.amount_total();
+        if pool.backend.amount_total() != amount {
+            return None;
+        }
+
+        let deadline = db::now() + pool.conf.min_validity(&backend.invoice);
+        let challenge = {
+            let mut invoices = pool.invoices.lock().unwrap();
+            loop {
+                let challenge = invoices.pop_front()?;
+                if challenge.expires_at() > deadline && lnd.can_issue(&challenge.node, &amount) {
+                    break challenge;
+                }
+            }
+        };
+        pool.refill.notify_one();
+        debug!(backend = backend.name, "Serving pooled invoice");
+        Some(challenge)
+    }
+}
+
+/// Keep the pool filled, invoices that can't be created now
+/// are retried on the next take or check
+async fn refill(pool: Arc<Pool>, lnd: lnd::Client) {
+    loop {
+        pool.expire();
+        if lnd.is_reachable() {
+            for _ in 0..pool.missing() {
+                match Challenge::issue(lnd.clone(), &pool.backend).await {
+                    Ok(challenge) => pool.invoices.lock().unwrap().push_back(challenge),
+                    Err(e) => {
+                        warn!(
+                            backend = pool.backend.name,
+                            error=%e,
+                            "Unable to refill invoice pool"
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        tokio::select! {
+            _ = pool.refill.notified() => {}
+            _ = sleep(CHECK_INTERVAL) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::{sha256, Hash};
+    use lightning::ln::PaymentSecret;
+    use lightning_invoice::{Currency, InvoiceBuilder};
+    use secp256k1::{Secp256k1, SecretKey};
+
+    use super::*;
+    use crate::{config::fixtures, lnd::Node};
+
+    fn pool(size: usize, refill_below: Option<usize>) -> Pool {
+        Pool {
+            conf: config::Pool {
+                size,
+                refill_below,
+                min_validity: None,
+            },
+            backend: fixtures::backend("gpt", "/gpt"),
+            invoices: Mutex::new(VecDeque::new()),
+            refill: Notify::new(),
+        }
+    }
+
+    /// Challenge issued by the `node`, with an invoice
+    /// valid for `expiry` seconds from now
+    fn challenge(node: &str, expiry: u64) -> Challenge {
+        let key = SecretKey::from_slice(&[42; 32]).unwrap();
+        let invoice = InvoiceBuilder::new(Currency::Regtest)
+            .description("test".to_string())
+            .payment_hash(sha256::Hash::from_inner(rand::random()))
+            .payment_secret(PaymentSecret([1; 32]))
+            .current_timestamp()
+            .min_final_cltv_expiry(144)
+            .expiry_time(Duration::from_secs(expiry))
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &key))
+            .unwrap();
+        Challenge {
+            token_id: rand::random(),
+            node: node.to_string(),
+            invoice,
+        }
+    }
+
+    fn fill(pool: &Pool, count: usize, expiry: u64) {
+        let mut invoices = pool.invoices.lock().unwrap();
+        for _ in 0..count {
+            invoices.push_back(challenge("mock", expiry));
+        }
+    }
+
+    #[test]
+    fn refill_threshold_defaults_to_half() {
+        assert_eq!(pool(10, None).conf.refill_below(), 5);
+        assert_eq!(pool(10, Some(3)).conf.refill_below(), 3);
+        // capped at the pool size
+        assert_eq!(pool(10, Some(20)).conf.refill_below(), 10);
+    }
+
+    #[test]
+    fn min_validity_defaults_to_half_the_expiry() {
+        let pool = pool(10, None);
+        assert_eq!(pool.conf.min_validity(&pool.backend.invoice), 300);
+        let conf = config::Pool {
+            min_validity: Some(60),
+            ..pool.conf.clone()
+        };
+        assert_eq!(conf.min_validity(&pool.backend.invoice), 60);
+    }
+
+    #[test]
+    fn fills_up_once_below_threshold() {
+        let pool = pool(10, Some(4));
+        assert_eq!(pool.missing(), 10);
+        fill(&pool, 4, 600);
+        assert_eq!(pool.missing(), 0);
+        pool.invoices.lock().unwrap().pop_front();
+        assert_eq!(pool.missing(), 7);
+    }
+
+    #[test]
+    fn drops_invoices_expiring_soon() {
+        let pool = pool(10, None);
+        fill(&pool, 2, 600);
+        fill(&pool, 3, 120);
+        pool.expire();
+        // min validity is half of the 600s invoice expiry
+        assert_eq!(pool.invoices.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn drops_invoices_of_unreachable_nodes() {
+        let pool = Arc::new(pool(10, None));
+        pool.invoices.lock().unwrap().extend([
+            challenge("alice", 600),
+            challenge("alice", 600),
+            challenge("bob", 600),
+        ]);
+        let backend = pool.backend.clone();
+        let invoices = InvoicePool {
+            pools: Arc::new(HashMap::from([(backend.name.clone(), pool.clone())])),
+        };
+        let (alice, bob) = (Node::mock("alice", 1), Node::mock("bob", 1));
+        let lnd = lnd::Client::new(vec![alice.clone(), bob]);
+
+        alice.take_down().await;
+        assert_eq!(invoices.take(&backend, &lnd).unwrap().node, "bob");
+        // invoices of the unreachable node are dropped, not kept for later
+        assert!(pool.invoices.lock().unwrap().is_empty());
+        assert!(invoices.take(&backend, &lnd).is_none());
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use warp::http::Method;
+
+use crate::config::Backend;
+
+/// Path parameters captured when matching a route pattern
+pub type Params = HashMap<String, String>;
+
+/// Single segment of a route pattern
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// Has to match the path segment exactly
+    Literal(String),
+    /// `{name}`, matches any path segment and captures it
+    Param(String),
+    /// `*`, matches any single path segment
+    Wildcard,
+    /// `**`, matches all the remaining path segments (if any)
+    Rest,
+}
+
+/// Route pattern, parsed from the backend `path`. Supported forms:
+/// exact `/gpt`, glob `/gpt/*`, prefix `/gpt/**` and parameterised
+/// `/models/{model}/generate`. Empty segments (trailing or duplicated
+/// slashes) are ignored.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub fn parse(path: &str) -> Self {
+        let mut segments = vec![];
+        for part in split(path) {
+            let segment = match part {
+                "**" => Segment::Rest,
+                "*" => Segment::Wildcard,
+                p if p.starts_with('{') && p.ends_with('}') && p.len() > 2 => {
+                    Segment::Param(p[1..p.len() - 1].to_string())
+                }
+                p => Segment::Literal(p.to_string()),
+            };
+            let is_rest = segment == Segment::Rest;
+            segments.push(segment);
+            // nothing can follow the catch-all
+            if is_rest {
+                break;
+            }
+        }
+        Self { segments }
+    }
+
+    /// Match the path against the pattern, returning the captured params
+    pub fn matches(&self, path: &str) -> Option<Params> {
+        let parts: Vec<&str> = split(path).collect();
+        let mut params = Params::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            if *segment == Segment::Rest {
+                return Some(params);
+            }
+            let part = parts.get(i)?;
+            match segment {
+                Segment::Literal(literal) if literal != part => return None,
+                Segment::Param(name) => {
+                    params.insert(name.to_string(), part.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    /// How specific the pattern is, the most specific one wins when
+    /// more patterns match the path: literal segments count first,
+    /// then the total number of segments, exact patterns beat prefixes.
+    fn specificity(&self) -> (usize, usize, bool) {
+        let literals = self
+            .segments
+            .iter()
+            .filter(|s| matches!(s, Segment::Literal(_)))
+            .count();
+        let is_prefix = self.segments.last() == Some(&Segment::Rest);
+        (literals, self.segments.len(), !is_prefix)
+    }
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+/// Reasons for not finding a backend for the request
+#[derive(Debug)]
+pub enum RouteError {
+    /// No backend matches the path
+    NotFound,
+    /// Backends match the path, but none of them accepts the method
+    MethodNotAllowed(Vec<String>),
+}
+
+/// Find the backend for the request, bound to the method and captured
+/// path parameters. Backends with a matching `host` take precedence
+/// over the ones accepting any host, then the longest match wins.
+pub fn find(
+    backends: &[Backend],
+    host: Option<&str>,
+    method: &Method,
+    path: &str,
+) -> Result<Backend, RouteError> {
+    let matching: Vec<_> = backends
+        .iter()
+        .filter(|b| match (&b.host, host) {
+            (None, _) => true,
+            (Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        })
+        .filter_map(|b| {
+            let pattern = Pattern::parse(&b.path);
+            pattern
+                .matches(path)
+                .map(|params| ((b.host.is_some(), pattern.specificity()), b, params))
+        })
+        .collect();
+
+    if matching.is_empty() {
+        return Err(RouteError::NotFound);
+    }
+
+    let allowed: Vec<String> = matching
+        .iter()
+        .flat_map(|(_, b, _)| b.methods.iter().cloned())
+        .map(|m| m.to_uppercase())
+        .unique()
+        .collect();
+
+    matching
+        .into_iter()
+        .filter(|(_, b, _)| b.allows(method))
+        .max_by_key(|(specificity, _, _)| *specificity)
+        .map(|(_, backend, params)| backend.bind(method, path, params))
+        .ok_or(RouteError::MethodNotAllowed(allowed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures;
+
+    fn backend(name: &str, path: &str, host: Option<&str>, methods: &[&str]) -> Backend {
+        Backend {
+            host: host.map(str::to_string),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            upstream: "http://localhost:8080/{model}".to_string(),
+            ..fixtures::backend(name, path)
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Option<Params> {
+        Some(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn exact_pattern() {
+        let pattern = Pattern::parse("/gpt");
+        assert_eq!(pattern.matches("/gpt"), params(&[]));
+        assert_eq!(pattern.matches("/gpt/"), params(&[]));
+        assert_eq!(pattern.matches("/gpt/chat"), None);
+        assert_eq!(pattern.matches("/"), None);
+    }
+
+    #[test]
+    fn glob_pattern_matches_single_segment() {
+        let pattern = Pattern::parse("/gpt/*");
+        assert_eq!(pattern.matches("/gpt/chat"), params(&[]));
+        assert_eq!(pattern.matches("/gpt"), None);
+        assert_eq!(pattern.matches("/gpt/chat/completions"), None);
+    }
+
+    #[test]
+    fn prefix_pattern_matches_the_rest() {
+        let pattern = Pattern::parse("/gpt/**");
+        assert_eq!(pattern.matches("/gpt"), params(&[]));
+        assert_eq!(pattern.matches("/gpt/chat/completions"), params(&[]));
+        assert_eq!(pattern.matches("/other/chat"), None);
+        // nothing can follow the catch-all
+        assert_eq!(Pattern::parse("/gpt/**/ignored").segments.len(), 2);
+    }
+
+    #[test]
+    fn param_pattern_captures_segments() {
+        let pattern = Pattern::parse("/models/{model}/generate");
+        assert_eq!(
+            pattern.matches("/models/llama//generate"),
+            params(&[("model", "llama")])
+        );
+        assert_eq!(pattern.matches("/models/generate"), None);
+        // `{}` is not a parameter
+        assert_eq!(
+            Pattern::parse("/{}").segments,
+            vec![Segment::Literal("{}".to_string())]
+        );
+    }
+
+    #[test]
+    fn more_literals_are_more_specific() {
+        let exact = Pattern::parse("/models/llama/generate").specificity();
+        let param = Pattern::parse("/models/{model}/generate").specificity();
+        let prefix = Pattern::parse("/models/**").specificity();
+        assert!(exact > param);
+        assert!(param > prefix);
+        assert!(Pattern::parse("/models/*").specificity() > prefix);
+    }
+
+    #[test]
+    fn finds_the_most_specific_backend() {
+        let backends = [
+            backend("prefix", "/models/**", None, &[]),
+            backend("param", "/models/{model}/generate", None, &[]),
+        ];
+        let found = find(&backends, None, &Method::POST, "/models/llama/generate").unwrap();
+        assert_eq!(found.name, "param");
+        assert_eq!(found.upstream, "http://localhost:8080/llama");
+        assert_eq!(found.request_path, "/models/llama/generate");
+
+        let found = find(&backends, None, &Method::POST, "/models/llama").unwrap();
+        assert_eq!(found.name, "prefix");
+        assert!(matches!(
+            find(&backends, None, &Method::POST, "/other"),
+            Err(RouteError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn host_backends_take_precedence() {
+        let backends = [
+            backend("any", "/models/{model}/generate", None, &[]),
+            backend("host", "/models/**", Some("api.example.com"), &[]),
+        ];
+        let found = find(
+            &backends,
+            Some("API.example.com"),
+            &Method::GET,
+            "/models/a/generate",
+        );
+        assert_eq!(found.unwrap().name, "host");
+        let found = find(
+            &backends,
+            Some("other.com"),
+            &Method::GET,
+            "/models/a/generate",
+        );
+        assert_eq!(found.unwrap().name, "any");
+    }
+
+    #[test]
+    fn reports_allowed_methods() {
+        let backends = [
+            backend("get", "/gpt", None, &["get"]),
+            backend("post", "/gpt", None, &["POST", "GET"]),
+        ];
+        assert_eq!(
+            find(&backends, None, &Method::POST, "/gpt").unwrap().name,
+            "post"
+        );
+        match find(&backends, None, &Method::DELETE, "/gpt") {
+            Err(RouteError::MethodNotAllowed(allowed)) => assert_eq!(allowed, vec!["GET", "POST"]),
+            other => panic!("unexpected {:?}", other.map(|b| b.name)),
+        }
+    }
+}
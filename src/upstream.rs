@@ -1,15 +1,164 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, bail};
+use bitcoin_hashes::{
+    hmac::{Hmac, HmacEngine},
+    sha256, Hash, HashEngine,
+};
 use hyper_tls::HttpsConnector;
-use serde_json::Value;
-use tracing::debug;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
 use warp::{
     http::HeaderValue,
-    hyper::{header::HeaderName, Body, Method, Request},
+    hyper::{
+        body::Bytes,
+        header::{self, HeaderName},
+        Body, HeaderMap, Method, Request, StatusCode,
+    },
+    reply::Response,
+    Reply,
+};
+
+use crate::{
+    config::{Backend, ResponseMode},
+    lsat::MiliSats,
 };
 
-use crate::config::Backend;
+/// Maximum length of the upstream error message we pass back
+/// to the client, longer bodies are truncated.
+const MAX_ERROR_MESSAGE_LEN: usize = 512;
+
+/// Failures when talking to the upstream server, carrying enough
+/// context to build a meaningful response for the client.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// Upstream responded with a non-2xx status code
+    Status { status: StatusCode, message: String },
+    /// Upstream did not respond within the configured timeout
+    Timeout,
+    /// Unable to reach the upstream server
+    Connect(String),
+    /// Upstream responded, but not with what we expected
+    InvalidResponse(String),
+}
+
+impl UpstreamError {
+    /// Status code the proxy responds with to the client.
+    /// Client side validation errors are passed through as-is,
+    /// everything else is reported as a gateway problem.
+    pub fn proxy_status(&self) -> StatusCode {
+        match self {
+            UpstreamError::Status { status, .. } if is_passthrough(status) => *status,
+            UpstreamError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Message that is safe to show to the client. Only the messages of
+    /// the passed through client errors are shown, others (e.g. a 401
+    /// echoing the rejected API key) could leak the upstream credentials.
+    pub fn message(&self) -> String {
+        match self {
+            UpstreamError::Status { status, message } if is_passthrough(status) => {
+                message.to_string()
+            }
+            UpstreamError::Status { .. } => "upstream error".to_string(),
+            UpstreamError::Timeout => "upstream timed out".to_string(),
+            UpstreamError::Connect(_) => "upstream unavailable".to_string(),
+            UpstreamError::InvalidResponse(e) => format!("invalid upstream response: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // message is left out as it can contain the credentials
+            UpstreamError::Status { status, .. } => {
+                write!(f, "upstream responded with {}", status)
+            }
+            UpstreamError::Timeout => write!(f, "upstream timed out"),
+            UpstreamError::Connect(e) => write!(f, "unable to connect to upstream: {}", e),
+            UpstreamError::InvalidResponse(e) => write!(f, "invalid upstream response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Upstream statuses caused by the data the client sent us,
+/// those are meaningful to the client so we pass them through.
+/// Auth errors (401/403) are our own misconfiguration and are not.
+fn is_passthrough(status: &StatusCode) -> bool {
+    matches!(
+        *status,
+        StatusCode::BAD_REQUEST
+            | StatusCode::NOT_FOUND
+            | StatusCode::CONFLICT
+            | StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::UNPROCESSABLE_ENTITY
+            | StatusCode::TOO_MANY_REQUESTS
+    )
+}
+
+/// Extract a human readable error message from the upstream
+/// error body, trying the common JSON error shapes first.
+fn error_message(body: &[u8]) -> String {
+    let message = match serde_json::from_slice::<Value>(body) {
+        Ok(json) => [
+            json.pointer("/error/message"),
+            json.get("message"),
+            json.get("error"),
+            json.get("detail"),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| json.to_string()),
+        Err(_) => String::from_utf8_lossy(body).trim().to_string(),
+    };
+    message.chars().take(MAX_ERROR_MESSAGE_LEN).collect()
+}
+
+/// Raw response received from the upstream server, before it
+/// gets shaped for the client. Cheap to clone, so it can be cached.
+#[derive(Debug, Clone)]
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Verified identity of the paying customer, forwarded
+/// to the upstream so it knows who is making the call.
+#[derive(Debug)]
+pub struct Caller {
+    pub token_id: String,
+    /// amount paid for the token
+    pub paid: MiliSats,
+    /// budget left, before this call gets charged
+    pub quota: MiliSats,
+    pub capabilities: String,
+}
+
+impl Caller {
+    /// Identity fields as (header suffix, value) pairs, in the
+    /// order they are covered by the signature
+    fn fields(&self, timestamp: u64) -> [(&'static str, String); 5] {
+        [
+            ("Token-Id", self.token_id.clone()),
+            ("Paid-Msat", self.paid.0.to_string()),
+            ("Quota-Msat", self.quota.0.to_string()),
+            ("Capabilities", self.capabilities.clone()),
+            ("Timestamp", timestamp.to_string()),
+        ]
+    }
+}
 
 /// Handing connnectivity and processing to the upstream
 /// server.
@@ -17,7 +166,9 @@ use crate::config::Backend;
 pub struct Upstream {
     backend: Backend,
     req: Option<Request<Body>>,
-    resp_data: Option<String>,
+    /// sha256 of the prepared request body
+    body_sha: sha256::Hash,
+    resp: Option<UpstreamResponse>,
 }
 
 impl Upstream {
@@ -26,10 +177,27 @@ impl Upstream {
         Self {
             backend,
             req: None,
-            resp_data: None,
+            body_sha: sha256::Hash::hash(&[]),
+            resp: None,
         }
     }
 
+    /// Create an object with an already known upstream response,
+    /// e.g. one that was served from the cache
+    pub fn with_response(backend: Backend, resp: UpstreamResponse) -> Self {
+        Self {
+            backend,
+            req: None,
+            body_sha: sha256::Hash::hash(&[]),
+            resp: Some(resp),
+        }
+    }
+
+    /// Raw upstream response, available after the call was made
+    pub fn response(&self) -> Option<&UpstreamResponse> {
+        self.resp.as_ref()
+    }
+
     /// Buld the request based on the input data that we want
     /// to forward and the Backend config.
     /// Using the builder pattern
@@ -37,24 +205,27 @@ impl Upstream {
         &'a mut self,
         indata: &HashMap<String, String>,
     ) -> Result<&'a mut Self, anyhow::Error> {
+        let method = match &self.backend.upstream_method {
+            Some(method) => Method::from_str(&method.to_uppercase())?,
+            None => self.backend.method.clone().unwrap_or(Method::POST),
+        };
         let mut req = warp::hyper::Request::builder()
-            .method(Method::POST)
+            .method(method.clone())
             .uri(&self.backend.upstream);
 
         let header = req.headers_mut().unwrap();
 
         for h in &self.backend.headers {
-            let hvec: Vec<&str> = h.split(':').collect();
-
-            let key = hvec.first().unwrap().trim();
-            let val = hvec.get(1).unwrap().trim();
-            header.insert(
-                HeaderName::from_str(key).unwrap(),
-                HeaderValue::from_str(val).unwrap(),
-            );
+            // injected values are often credentials, keep them out of the logs
+            let mut value = HeaderValue::from_str(h.value.expose())?;
+            value.set_sensitive(true);
+            header.insert(HeaderName::from_str(&h.name)?, value);
         }
 
-        let mut body: Value = serde_json::from_str(&self.backend.body).unwrap();
+        let mut body: Value = match self.backend.body.trim() {
+            "" => json!({}),
+            template => serde_json::from_str(template)?,
+        };
 
         // update body with data provided by the user in the
         // inboud request
@@ -62,8 +233,62 @@ impl Upstream {
             .iter()
             .for_each(|(k, v)| body[k] = v.to_owned());
 
-        debug!("Prepared request {:?}, with body {:?}", req, body);
-        self.req = Some(req.body(Body::from(body.to_string()))?);
+        debug!(%method, upstream = self.backend.upstream, ?body, "Prepared request");
+        // requests of these methods carry no body
+        let body = match method {
+            Method::GET | Method::HEAD => String::new(),
+            _ => body.to_string(),
+        };
+        self.body_sha = sha256::Hash::hash(body.as_bytes());
+        self.req = Some(req.body(Body::from(body))?);
+        Ok(self)
+    }
+
+    /// Inject the identity headers of the caller into the prepared
+    /// request, if the backend is configured to forward them. Headers
+    /// are signed with HMAC-SHA256 over their newline-joined values
+    /// followed by the request method, path and hex sha256 of the body,
+    /// so the signature can't be replayed with another request.
+    pub fn identify<'a>(&'a mut self, caller: &Caller) -> Result<&'a mut Self, anyhow::Error> {
+        let identity = match &self.backend.identity {
+            Some(identity) => identity,
+            None => return Ok(self),
+        };
+        let req = self
+            .req
+            .as_mut()
+            .ok_or_else(|| anyhow!("Request not ready"))?;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let fields = caller.fields(timestamp);
+        let request = [
+            req.method().to_string(),
+            req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string(),
+            hex::encode(self.body_sha.into_inner()),
+        ];
+
+        let mut engine = HmacEngine::<sha256::Hash>::new(identity.secret.expose().as_bytes());
+        engine.input(
+            fields
+                .iter()
+                .map(|(_, v)| v.as_str())
+                .chain(request.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join("\n")
+                .as_bytes(),
+        );
+        let signature = Hmac::<sha256::Hash>::from_engine(engine);
+
+        let headers = req.headers_mut();
+        for (name, value) in fields
+            .into_iter()
+            .chain([("Signature", hex::encode(signature.into_inner()))])
+        {
+            headers.insert(
+                HeaderName::from_str(&format!("{}{}", identity.header_prefix, name))?,
+                HeaderValue::from_str(&value)?,
+            );
+        }
         Ok(self)
     }
 
@@ -76,21 +301,96 @@ impl Upstream {
             .req
             .take()
             .ok_or_else(|| anyhow!("Request not ready"))?;
-        let resp = client.request(req).await?;
 
-        // extract user_wallet_id, user_id, user_admin_key
-        let bytes = warp::hyper::body::to_bytes(resp).await?;
-        self.resp_data = Some(String::from_utf8(bytes.to_vec())?);
+        // the timeout covers the whole exchange, the body included
+        let exchange = async {
+            let resp = client
+                .request(req)
+                .await
+                .map_err(|e| UpstreamError::Connect(e.to_string()))?;
+            let (parts, body) = resp.into_parts();
+            let bytes = warp::hyper::body::to_bytes(body)
+                .await
+                .map_err(|e| UpstreamError::Connect(e.to_string()))?;
+            Ok::<_, UpstreamError>((parts, bytes))
+        };
+        let (parts, bytes) = match self.backend.upstream_timeout {
+            Some(secs) => tokio::time::timeout(Duration::from_secs(secs), exchange)
+                .await
+                .map_err(|_| UpstreamError::Timeout)?,
+            None => exchange.await,
+        }?;
+
+        if !parts.status.is_success() {
+            return Err(UpstreamError::Status {
+                status: parts.status,
+                message: error_message(&bytes),
+            }
+            .into());
+        }
+
+        self.resp = Some(UpstreamResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: bytes,
+        });
         Ok(self)
     }
 
-    /// Parse the response from the upstream server
-    pub fn parse<'a>(&'a mut self) -> Result<String, anyhow::Error> {
-        let data = self
-            .resp_data
+    /// Charge reported by the upstream for the call, bounded by the
+    /// configured maximum. `None` when the backend doesn't report charges
+    /// or the reported value is missing or invalid.
+    pub fn charge(&self) -> Option<MiliSats> {
+        let conf = self.backend.upstream_charge.as_ref()?;
+        let resp = self.resp.as_ref()?;
+
+        let charge = resp
+            .headers
+            .get(conf.header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u32>().ok());
+
+        if charge.is_none() {
+            warn!(backend = self.backend.name, header = conf.header, "Upstream charge not reported");
+        }
+        charge.map(|c| MiliSats(c.min(conf.max_msat)))
+    }
+
+    /// Build the response for the client, shaped according
+    /// to the `response_mode` of the backend
+    pub fn respond(&mut self) -> Result<Response, anyhow::Error> {
+        let UpstreamResponse {
+            status,
+            headers,
+            body: bytes,
+        } = self
+            .resp
             .take()
             .ok_or_else(|| anyhow!("Response data not ready"))?;
-        let mut root: &Value = &serde_json::from_str(&data)?;
+
+        let resp = match self.backend.response_mode {
+            ResponseMode::Raw => {
+                let mut resp = Response::new(Body::from(bytes));
+                *resp.status_mut() = status;
+                *resp.headers_mut() = passthrough_headers(headers);
+                resp
+            }
+            ResponseMode::Json => warp::reply::json(&self.parse(&bytes)?).into_response(),
+            ResponseMode::Text => as_text(self.parse(&bytes)?)?.into_response(),
+            ResponseMode::Paragraphs => {
+                let data = as_text(self.parse(&bytes)?)?;
+                let paragraphs: Vec<&str> = data.trim().split("\n\n").collect();
+                warp::reply::json(&json!({ "data": paragraphs })).into_response()
+            }
+        };
+        Ok(resp)
+    }
+
+    /// Parse the JSON response from the upstream server and extract
+    /// the value pointed to by the `response_fields` definition
+    fn parse(&self, data: &[u8]) -> Result<Value, anyhow::Error> {
+        let mut root: &Value = &serde_json::from_slice(data)
+            .map_err(|e| UpstreamError::InvalidResponse(e.to_string()))?;
         debug!("JSON-parsed response {:?}", root);
 
         // parse values to forward to client based
@@ -99,20 +399,48 @@ impl Upstream {
             .backend
             .response_fields
             .split('.')
-            .collect::<Vec<&str>>()
+            .filter(|f| !f.is_empty())
         {
             root = match field.parse::<usize>() {
-                Ok(num) => root.get(num).unwrap(),
-                Err(_) => root.get(field).unwrap(),
+                Ok(num) => root.get(num),
+                Err(_) => root.get(field),
             }
+            .ok_or_else(|| {
+                UpstreamError::InvalidResponse(format!("missing response field '{}'", field))
+            })?
         }
-        Ok(root
-            .as_str()
-            .ok_or_else(|| anyhow!("Unable to convert to string"))?
-            .to_string())
+        Ok(root.to_owned())
     }
 }
 
+/// Extracted value must be a string for text based response modes
+fn as_text(value: Value) -> Result<String, UpstreamError> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(UpstreamError::InvalidResponse(
+            "field is not a string".to_string(),
+        )),
+    }
+}
+
+/// Upstream response headers we can safely forward to the client,
+/// connection specific (hop-by-hop) ones are dropped.
+fn passthrough_headers(mut headers: HeaderMap) -> HeaderMap {
+    for name in [
+        header::CONNECTION,
+        header::CONTENT_LENGTH,
+        header::PROXY_AUTHENTICATE,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+    headers
+}
+
 /// Validates input fields and passes only the ones that
 /// we defined as desirable
 fn parse_indata(
@@ -123,12 +451,100 @@ fn parse_indata(
     for (key, ktype) in pass_fields.iter() {
         let val = indata.get(key).ok_or_else(|| anyhow!("No key in indata"))?;
         let casted = match ktype.as_str() {
-            "string" => Value::try_from(val.to_string()),
-            "int" => Value::try_from(val.parse::<i32>()?),
-            "float" => Value::try_from(val.parse::<f32>()?),
+            "string" => Value::from(val.to_string()),
+            "int" => Value::from(val.parse::<i32>()?),
+            "float" => Value::from(val.parse::<f32>()?),
             _ => bail!("Unknown field type: {}", ktype),
-        }?;
+        };
         out.insert(key.to_string(), casted);
     }
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    use crate::config::{fixtures, Identity, Secret};
+
+    fn caller() -> Caller {
+        Caller {
+            token_id: "ab".repeat(32),
+            paid: MiliSats(1000),
+            quota: MiliSats(900),
+            capabilities: "chat".to_string(),
+        }
+    }
+
+    /// Signature as the upstream computes it, following the README
+    fn signature(secret: &str, req: &Request<Body>, body: &str) -> String {
+        let headers = req.headers();
+        let fields = ["Token-Id", "Paid-Msat", "Quota-Msat", "Capabilities", "Timestamp"];
+        let mut values: Vec<String> = fields
+            .iter()
+            .map(|name| headers[format!("X-Lsat-{}", name).as_str()].to_str().unwrap().to_string())
+            .collect();
+        values.push(req.method().to_string());
+        values.push(req.uri().path().to_string());
+        values.push(hex::encode(sha256::Hash::hash(body.as_bytes()).into_inner()));
+
+        let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+        engine.input(values.join("\n").as_bytes());
+        hex::encode(Hmac::<sha256::Hash>::from_engine(engine).into_inner())
+    }
+
+    #[test]
+    fn signature_covers_the_request() {
+        let backend = Backend {
+            upstream: "http://localhost:8080/v1/chat".to_string(),
+            body: r#"{"model": "gpt"}"#.to_string(),
+            identity: Some(Identity {
+                secret: Secret::from("shared".to_string()),
+                header_prefix: "X-Lsat-".to_string(),
+            }),
+            ..fixtures::backend("gpt", "/gpt")
+        };
+        let mut upstream = Upstream::new(backend);
+        upstream
+            .build(&HashMap::new())
+            .unwrap()
+            .identify(&caller())
+            .unwrap();
+
+        let req = upstream.req.as_ref().unwrap();
+        let expected = signature("shared", req, r#"{"model":"gpt"}"#);
+        assert_eq!(req.headers()["X-Lsat-Signature"], expected);
+        // another method, path or body changes the signature
+        assert_ne!(signature("shared", req, "{}"), expected);
+    }
+
+    #[tokio::test]
+    async fn timeout_covers_the_body() {
+        // headers are sent right away, the body never completes
+        let route = warp::any().map(|| {
+            let (sender, body) = Body::channel();
+            std::mem::forget(sender);
+            warp::reply::Response::new(body)
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let backend = Backend {
+            upstream: format!("http://{}", addr),
+            upstream_timeout: Some(1),
+            ..fixtures::backend("gpt", "/gpt")
+        };
+        let mut upstream = Upstream::new(backend);
+        let err = upstream
+            .build(&HashMap::new())
+            .unwrap()
+            .make()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UpstreamError>(),
+            Some(UpstreamError::Timeout)
+        ));
+    }
+}